@@ -0,0 +1,295 @@
+use crate::messages::t;
+use crate::settings::ChatSettings;
+use crate::store::Store;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::{HashMap, HashSet},
+  time::{SystemTime, UNIX_EPOCH},
+};
+use teloxide::types::{ChatId, Message, UserId};
+
+const ACL_FILE: &str = "acl.json";
+const DEFAULT_DAILY_JOBS: u32 = 50;
+const DEFAULT_DAILY_MB: u64 = 2_000;
+
+lazy_static! {
+  static ref ACL: Store<Acl> = {
+    let acl = Store::open(ACL_FILE);
+    // bootstrap the admin list so there's always somebody who can run /allow
+    let admins = std::env::var("SMEE_ADMINS")
+      .ok()
+      .or(option_env!("SMEE_ADMINS").map(String::from));
+    if let Some(admins) = admins {
+      acl.update(|acl: &mut Acl| {
+        acl
+          .admins
+          .extend(admins.split(',').filter_map(|id| id.trim().parse::<u64>().ok()))
+      });
+    }
+    warn_if_locked(&acl.read());
+    acl
+  };
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+  /// Anybody who isn't on a deny list may use the bot. Where a new install starts,
+  /// so upgrading doesn't lock everybody out.
+  #[default]
+  Open,
+  /// Only allowlisted users and chats may use the bot.
+  Allowlist,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Quota {
+  pub jobs: u32,
+  pub bytes: u64,
+}
+
+impl Default for Quota {
+  fn default() -> Self {
+    Self {
+      jobs: DEFAULT_DAILY_JOBS,
+      bytes: DEFAULT_DAILY_MB * 1_000_000,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+pub struct Usage {
+  day: u64,
+  pub jobs: u32,
+  pub bytes: u64,
+}
+
+impl Usage {
+  /// Usage for the current day, resetting the counters when the day rolled over.
+  fn today(&mut self) -> &mut Self {
+    let today = today();
+    if self.day != today {
+      *self = Self {
+        day: today,
+        ..Self::default()
+      };
+    }
+    self
+  }
+
+  /// Like `today`, on a copy, for looking without saving anything.
+  fn current(mut self) -> Self {
+    *self.today()
+  }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Acl {
+  pub mode: Mode,
+  pub admins: HashSet<u64>,
+  pub allowed_users: HashSet<u64>,
+  pub denied_users: HashSet<u64>,
+  pub allowed_chats: HashSet<i64>,
+  pub denied_chats: HashSet<i64>,
+  pub default_quota: Quota,
+  pub quotas: HashMap<u64, Quota>,
+  usage: HashMap<u64, Usage>,
+}
+
+impl Acl {
+  fn quota(&self, user: u64) -> Quota {
+    self
+      .quotas
+      .get(&user)
+      .copied()
+      .unwrap_or(self.default_quota)
+  }
+}
+
+pub enum Access {
+  Granted,
+  Denied,
+  OverQuota { quota: Quota, usage: Usage },
+}
+
+impl Access {
+  pub fn refusal(&self, settings: &ChatSettings) -> String {
+    match self {
+      Self::Granted => String::new(),
+      Self::Denied => t(settings, "denied").into(),
+      Self::OverQuota { quota, usage } => t(settings, "over_quota")
+        .arg("jobs", usage.jobs)
+        .arg("max_jobs", quota.jobs)
+        .arg("mb", usage.bytes / 1_000_000)
+        .arg("max_mb", quota.bytes / 1_000_000)
+        .into(),
+    }
+  }
+}
+
+pub enum Target {
+  User(u64),
+  Chat(i64),
+  Admin(u64),
+  Everyone,
+}
+
+impl Target {
+  /// Parses `user <id>`, `chat <id>`, `admin <id>` or `everyone`.
+  /// The id may be left out when replying to somebody's message.
+  pub fn parse(args: &str, msg: &Message) -> Option<Self> {
+    let mut args = args.split_whitespace();
+    let kind = args.next()?;
+    let replied = msg.reply_to_message();
+    let user = || match args.clone().next() {
+      Some(id) => id.parse().ok(),
+      None => replied.and_then(|m| m.from()).map(|u| u.id.0),
+    };
+
+    Some(match kind {
+      "user" => Self::User(user()?),
+      "admin" => Self::Admin(user()?),
+      "chat" => Self::Chat(match args.clone().next() {
+        Some(id) => id.parse().ok()?,
+        None => msg.chat.id.0,
+      }),
+      "everyone" => Self::Everyone,
+      _ => return None,
+    })
+  }
+}
+
+/// Complains when nobody but admins could use the bot, or nobody could manage it.
+fn warn_if_locked(acl: &Acl) {
+  if acl.admins.is_empty() {
+    warn!("No admins in {ACL_FILE}, set SMEE_ADMINS to manage who may use me!");
+  }
+  if acl.mode == Mode::Allowlist && acl.allowed_users.is_empty() && acl.allowed_chats.is_empty() {
+    warn!("The allowlist in {ACL_FILE} is empty, only admins may use me!");
+  }
+}
+
+pub fn is_admin(user: Option<UserId>) -> bool {
+  user.is_some_and(|user| ACL.read().admins.contains(&user.0))
+}
+
+/// Decides whether the sender of `msg` may start a new job. Jobs are counted
+/// separately by `record_job`, once there's something to do.
+pub fn check(msg: &Message) -> Access {
+  let user = msg.from().map(|u| u.id);
+  let chat = msg.chat.id;
+
+  if is_admin(user) {
+    return Access::Granted;
+  }
+
+  let acl = ACL.read();
+  if !allowed(&acl, user, chat) {
+    return Access::Denied;
+  }
+
+  let Some(user) = user else {
+    return Access::Granted;
+  };
+
+  let (quota, usage) = usage_of(&acl, user);
+  if usage.jobs >= quota.jobs || usage.bytes >= quota.bytes {
+    return Access::OverQuota { quota, usage };
+  }
+  Access::Granted
+}
+
+/// Counts a job against today's quota of `user`. Admins don't have one.
+pub fn record_job(user: Option<UserId>) {
+  let Some(user) = user.filter(|&user| !is_admin(Some(user))) else {
+    return;
+  };
+  ACL.update(|acl| acl.usage.entry(user.0).or_default().today().jobs += 1);
+}
+
+fn allowed(acl: &Acl, user: Option<UserId>, chat: ChatId) -> bool {
+  let user = user.map(|u| u.0);
+  if user.is_some_and(|u| acl.denied_users.contains(&u)) || acl.denied_chats.contains(&chat.0) {
+    return false;
+  }
+
+  match acl.mode {
+    Mode::Open => true,
+    Mode::Allowlist => {
+      user.is_some_and(|u| acl.allowed_users.contains(&u)) || acl.allowed_chats.contains(&chat.0)
+    }
+  }
+}
+
+/// Adds bytes sent or hosted for `user` to today's usage.
+pub fn record_bytes(user: Option<UserId>, bytes: u64) {
+  let Some(user) = user else {
+    return;
+  };
+  ACL.update(|acl| acl.usage.entry(user.0).or_default().today().bytes += bytes);
+}
+
+pub fn allow(target: Target) {
+  ACL.update(|acl| match target {
+    Target::User(id) => {
+      acl.denied_users.remove(&id);
+      acl.allowed_users.insert(id);
+    }
+    Target::Chat(id) => {
+      acl.denied_chats.remove(&id);
+      acl.allowed_chats.insert(id);
+    }
+    Target::Admin(id) => {
+      acl.admins.insert(id);
+    }
+    Target::Everyone => acl.mode = Mode::Open,
+  });
+}
+
+pub fn deny(target: Target) {
+  ACL.update(|acl| match target {
+    Target::User(id) => {
+      acl.allowed_users.remove(&id);
+      acl.denied_users.insert(id);
+    }
+    Target::Chat(id) => {
+      acl.allowed_chats.remove(&id);
+      acl.denied_chats.insert(id);
+    }
+    Target::Admin(id) => {
+      acl.admins.remove(&id);
+    }
+    Target::Everyone => {
+      acl.mode = Mode::Allowlist;
+      warn_if_locked(acl);
+    }
+  });
+}
+
+/// Sets the daily quota of `user`, or the default quota when `user` is `None`.
+pub fn set_quota(user: Option<u64>, quota: Quota) {
+  ACL.update(|acl| match user {
+    Some(user) => {
+      acl.quotas.insert(user, quota);
+    }
+    None => acl.default_quota = quota,
+  });
+}
+
+pub fn usage(user: UserId) -> (Quota, Usage) {
+  usage_of(&ACL.read(), user)
+}
+
+fn usage_of(acl: &Acl, user: UserId) -> (Quota, Usage) {
+  let usage = acl.usage.get(&user.0).copied().unwrap_or_default();
+  (acl.quota(user.0), usage.current())
+}
+
+fn today() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs() / 86_400)
+    .unwrap_or_default()
+}
@@ -1,14 +1,62 @@
 use anyhow::Result;
 use awsregion::Region;
-use s3::{creds::Credentials, Bucket};
+use s3::{creds::Credentials, error::S3Error, serde_types::Object, Bucket};
 use std::path::Path;
 use tokio::fs::File;
 
-pub async fn put_vid(s3_path: &str, file_path: &Path) -> Result<()> {
-  put("v-kota", s3_path, file_path).await
+pub async fn put(bucket: &str, s3_path: &str, file_path: &Path) -> Result<()> {
+  let bucket = bucket_handle(bucket);
+  let mut reader = File::open(file_path).await?;
+  bucket.put_object_stream(&mut reader, s3_path).await?;
+
+  Ok(())
 }
 
-pub async fn put(bucket: &str, s3_path: &str, file_path: &Path) -> Result<()> {
+pub async fn delete(bucket: &str, s3_path: &str) -> Result<()> {
+  bucket_handle(bucket).delete_object(s3_path).await?;
+
+  Ok(())
+}
+
+/// Everything in the bucket under `prefix`, as it is in B2 rather than in our index.
+pub async fn list(bucket: &str, prefix: &str) -> Result<Vec<Object>> {
+  let pages = bucket_handle(bucket).list(prefix.to_owned(), None).await?;
+
+  Ok(pages.into_iter().flat_map(|page| page.contents).collect())
+}
+
+/// What's directly under `prefix`, like a directory: the prefixes one level down,
+/// and the files.
+pub async fn list_dir(bucket: &str, prefix: &str) -> Result<(Vec<String>, Vec<Object>)> {
+  let pages = bucket_handle(bucket)
+    .list(prefix.to_owned(), Some("/".to_owned()))
+    .await?;
+
+  let mut dirs = vec![];
+  let mut files = vec![];
+  for page in pages {
+    dirs.extend(
+      page
+        .common_prefixes
+        .unwrap_or_default()
+        .into_iter()
+        .map(|dir| dir.prefix),
+    );
+    files.extend(page.contents);
+  }
+  Ok((dirs, files))
+}
+
+/// Whether `s3_path` is in the bucket, by HEAD.
+pub async fn exists(bucket: &str, s3_path: &str) -> Result<bool> {
+  match bucket_handle(bucket).head_object(s3_path).await {
+    Ok((_, 404)) | Err(S3Error::Http(404, _)) => Ok(false),
+    Ok(_) => Ok(true),
+    Err(err) => Err(err.into()),
+  }
+}
+
+fn bucket_handle(bucket: &str) -> Bucket {
   let region = Region::Custom {
     region: "us-west-001".to_owned(),
     endpoint: "s3.us-west-001.backblazeb2.com".to_owned(),
@@ -22,9 +70,5 @@ pub async fn put(bucket: &str, s3_path: &str, file_path: &Path) -> Result<()> {
   )
   .unwrap();
 
-  let bucket = Bucket::new(bucket, region, creds).unwrap();
-  let mut reader = File::open(file_path).await?;
-  bucket.put_object_stream(&mut reader, s3_path).await?;
-
-  Ok(())
+  Bucket::new(bucket, region, creds).unwrap()
 }
@@ -0,0 +1,831 @@
+use crate::config::{self, CacheConfig};
+use crate::http::Validators;
+use crate::stats;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+  cmp::Reverse,
+  collections::{BTreeMap, HashMap, HashSet},
+  io::SeekFrom,
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicU64, Ordering},
+  time::SystemTime,
+};
+use tokio::{
+  fs,
+  io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+  sync::mpsc,
+};
+use warp::hyper::body::{Body, Bytes};
+
+/// How much of a file on disk is read at a time when serving it.
+const READ_CHUNK: usize = 64 * 1024;
+
+lazy_static! {
+  /// Small files, checked before the disk.
+  static ref MEMORY: Mutex<Lru> = Mutex::default();
+  /// Everything in the cache directory, by key.
+  static ref DISK: Mutex<HashMap<String, DiskEntry>> = Mutex::default();
+  /// The newest fill being written to disk for each key. `evict` takes keys out, so
+  /// a fill that started before it can't put the file back.
+  static ref WRITING: Mutex<HashMap<String, u64>> = Mutex::default();
+}
+
+static FILLS: AtomicU64 = AtomicU64::new(0);
+
+struct Memory {
+  body: Bytes,
+  validators: Validators,
+  hits: u64,
+  stored: SystemTime,
+}
+
+/// Files in memory, dropping the least recently used once they're over a byte budget.
+#[derive(Default)]
+struct Lru {
+  entries: HashMap<String, (Memory, u64)>,
+  /// Keys by the tick they were last used at, oldest first.
+  order: BTreeMap<u64, String>,
+  tick: u64,
+  bytes: u64,
+}
+
+impl Lru {
+  fn get(&mut self, key: &str) -> Option<&mut Memory> {
+    self.tick += 1;
+    let (memory, used) = self.entries.get_mut(key)?;
+    self.order.remove(used);
+    *used = self.tick;
+    self.order.insert(self.tick, key.to_owned());
+    Some(memory)
+  }
+
+  fn insert(&mut self, key: &str, memory: Memory, conf: &CacheConfig) {
+    let size = memory.body.len() as u64;
+    if !fits_in_memory(conf, size) {
+      return;
+    }
+    let budget = conf.memory_budget_mb * 1_000_000;
+
+    self.remove(key);
+    while self.bytes + size > budget {
+      let Some((_, oldest)) = self.order.pop_first() else {
+        break;
+      };
+      if let Some((evicted, _)) = self.entries.remove(&oldest) {
+        self.bytes -= evicted.body.len() as u64;
+      }
+    }
+
+    self.tick += 1;
+    self.bytes += size;
+    self.order.insert(self.tick, key.to_owned());
+    self.entries.insert(key.to_owned(), (memory, self.tick));
+  }
+
+  fn remove(&mut self, key: &str) -> Option<Memory> {
+    let (memory, used) = self.entries.remove(key)?;
+    self.order.remove(&used);
+    self.bytes -= memory.body.len() as u64;
+    Some(memory)
+  }
+
+  fn clear(&mut self) -> Vec<String> {
+    self.order.clear();
+    self.bytes = 0;
+    self.entries.drain().map(|(key, _)| key).collect()
+  }
+}
+
+/// Written next to each cached file as `<name>.json`, so the disk index can be
+/// rebuilt at startup.
+#[derive(Serialize, Deserialize, Clone)]
+struct Meta {
+  key: String,
+  size: u64,
+  validators: Validators,
+}
+
+struct DiskEntry {
+  meta: Meta,
+  last_used: SystemTime,
+  hits: u64,
+  stored: SystemTime,
+}
+
+/// A cached file, ready to be read.
+pub struct Hit {
+  pub validators: Validators,
+  pub size: u64,
+  data: Data,
+}
+
+enum Data {
+  Memory(Bytes),
+  /// Opened when it was looked up, so eviction can't pull it out from under us.
+  Disk(fs::File),
+}
+
+impl Hit {
+  /// `len` bytes from `start`, streamed off the disk if that's where they are.
+  pub fn read(self, start: u64, len: u64) -> Body {
+    match self.data {
+      Data::Memory(bytes) => Body::from(bytes.slice(start as usize..(start + len) as usize)),
+      Data::Disk(mut file) => Body::wrap_stream(async_stream::stream! {
+        if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+          yield Err(e);
+          return;
+        }
+        let mut left = len;
+        let mut buf = vec![0; READ_CHUNK];
+        while left > 0 {
+          let want = left.min(READ_CHUNK as u64) as usize;
+          match file.read(&mut buf[..want]).await {
+            Ok(0) => break,
+            Ok(n) => {
+              left -= n as u64;
+              yield Ok(Bytes::copy_from_slice(&buf[..n]));
+            }
+            Err(e) => {
+              yield Err(e);
+              break;
+            }
+          }
+        }
+      }),
+    }
+  }
+}
+
+/// Looks in memory, then on disk. Small files found on disk are pulled back into memory.
+pub async fn get(key: &str) -> Option<Hit> {
+  let hit = lookup(key).await;
+  match &hit {
+    Some(_) => stats::incr(&stats::CACHE_HITS, 1),
+    None => stats::incr(&stats::CACHE_MISSES, 1),
+  }
+  hit
+}
+
+async fn lookup(key: &str) -> Option<Hit> {
+  let in_memory = MEMORY.lock().get(key).map(|memory| {
+    memory.hits += 1;
+    Hit {
+      validators: memory.validators.clone(),
+      size: memory.body.len() as u64,
+      data: Data::Memory(memory.body.clone()),
+    }
+  });
+  if let Some(hit) = in_memory {
+    stats::incr(&stats::MEMORY_HITS, 1);
+    // or the disk would think the files hit most in memory are the ones nobody wants
+    if let Some(entry) = DISK.lock().get_mut(key) {
+      entry.last_used = SystemTime::now();
+    }
+    return Some(hit);
+  }
+
+  let meta = {
+    let mut disk = DISK.lock();
+    let entry = disk.get_mut(key)?;
+    entry.last_used = SystemTime::now();
+    entry.hits += 1;
+    entry.meta.clone()
+  };
+  let path = dir().join(file_name(key));
+
+  let data = match fits_in_memory(conf(), meta.size) {
+    true => fs::read(&path).await.map(|body| Data::Memory(body.into())),
+    false => fs::File::open(&path).await.map(Data::Disk),
+  };
+  match data {
+    Ok(data) => {
+      if let Data::Memory(body) = &data {
+        remember(key, body.clone(), meta.validators.clone());
+      }
+      Some(Hit {
+        validators: meta.validators,
+        size: meta.size,
+        data,
+      })
+    }
+    Err(e) => {
+      warn!("Cached {key} went missing: {e}");
+      DISK.lock().remove(key);
+      None
+    }
+  }
+}
+
+fn remember(key: &str, body: Bytes, validators: Validators) {
+  info!("Cached: {key}: {}", body.len());
+  MEMORY.lock().insert(
+    key,
+    Memory {
+      body,
+      validators,
+      hits: 0,
+      stored: SystemTime::now(),
+    },
+    conf(),
+  );
+}
+
+fn fits_in_memory(conf: &CacheConfig, size: u64) -> bool {
+  size <= conf.memory_object_mb * 1_000_000 && size <= conf.memory_budget_mb * 1_000_000
+}
+
+/// A budget of 0 turns the disk cache off, even for empty files.
+fn fits_on_disk(conf: &CacheConfig, size: u64) -> bool {
+  conf.disk_budget_mb > 0 && size <= disk_budget(conf)
+}
+
+enum Fill {
+  Chunk(Bytes),
+  Done,
+}
+
+/// Collects a file as it streams past on its way to a client, and caches it once
+/// it has all arrived. Dropping it before `finish` throws away what it has.
+pub struct Filler {
+  key: String,
+  validators: Validators,
+  expected: u64,
+  received: u64,
+  memory: Option<Vec<u8>>,
+  disk: Option<mpsc::UnboundedSender<Fill>>,
+}
+
+/// Whether a file of `size` bytes would be cached anywhere.
+pub fn fits(size: u64) -> bool {
+  fits_in_memory(conf(), size) || fits_on_disk(conf(), size)
+}
+
+/// Starts caching `key`, if a file of `size` bytes fits anywhere.
+pub fn fill(key: &str, validators: Validators, size: Option<u64>) -> Option<Filler> {
+  let size = size?;
+  let memory = fits_in_memory(conf(), size).then(|| Vec::with_capacity(size as usize));
+  let disk = fits_on_disk(conf(), size).then(|| {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let meta = Meta {
+      key: key.to_owned(),
+      size,
+      validators: validators.clone(),
+    };
+    tokio::spawn(write_to_disk(
+      dir().to_owned(),
+      start_writing(key),
+      meta,
+      rx,
+    ));
+    tx
+  });
+  if memory.is_none() && disk.is_none() {
+    return None;
+  }
+
+  Some(Filler {
+    key: key.to_owned(),
+    validators,
+    expected: size,
+    received: 0,
+    memory,
+    disk,
+  })
+}
+
+impl Filler {
+  pub fn push(&mut self, chunk: &Bytes) {
+    self.received += chunk.len() as u64;
+    if let Some(memory) = &mut self.memory {
+      memory.extend_from_slice(chunk);
+    }
+    if let Some(disk) = &self.disk {
+      if disk.send(Fill::Chunk(chunk.clone())).is_err() {
+        self.disk = None;
+      }
+    }
+  }
+
+  pub fn finish(self) {
+    if self.received != self.expected {
+      warn!(
+        "{} was {} bytes, expected {}, not caching it.",
+        self.key, self.received, self.expected
+      );
+      return;
+    }
+    if let Some(memory) = self.memory {
+      remember(&self.key, memory.into(), self.validators);
+    }
+    if let Some(disk) = self.disk {
+      let _ = disk.send(Fill::Done);
+    }
+  }
+}
+
+/// Makes a new fill of `key` the one that gets to keep what it writes.
+fn start_writing(key: &str) -> u64 {
+  let fill = FILLS.fetch_add(1, Ordering::Relaxed);
+  WRITING.lock().insert(key.to_owned(), fill);
+  fill
+}
+
+/// Writes a file to `<name>.<random>.part` as it arrives and renames it into place
+/// once it's complete, so a half written file is never served.
+async fn write_to_disk(dir: PathBuf, fill: u64, meta: Meta, mut rx: mpsc::UnboundedReceiver<Fill>) {
+  let name = file_name(&meta.key);
+  let part = dir.join(format!("{name}.{:08x}.part", rand::random::<u32>()));
+
+  let written: Result<bool> = async {
+    fs::create_dir_all(&dir).await?;
+    let mut file = fs::File::create(&part).await?;
+    while let Some(fill) = rx.recv().await {
+      match fill {
+        Fill::Chunk(bytes) => file.write_all(&bytes).await?,
+        Fill::Done => {
+          file.flush().await?;
+          return Ok(true);
+        }
+      }
+    }
+    Ok(false)
+  }
+  .await;
+
+  let committed = match written {
+    Ok(true) => commit(&dir, &part, &name, &meta).await.map(|()| true),
+    other => other,
+  };
+  let key = meta.key.clone();
+  match committed {
+    Ok(true) => match keep(fill, meta) {
+      Kept::Yes => trim_disk().await,
+      Kept::Evicted => {
+        info!("{key} was evicted while it was being cached.");
+        let _ = fs::remove_file(dir.join(&name)).await;
+        let _ = fs::remove_file(dir.join(format!("{name}.json"))).await;
+      }
+      // the newer fill's files replace ours, or already have
+      Kept::Replaced => {}
+    },
+    Ok(false) => {
+      let _ = fs::remove_file(&part).await;
+    }
+    Err(e) => {
+      warn!("Couldn't cache {key} on disk: {e:?}");
+      let _ = fs::remove_file(&part).await;
+    }
+  }
+  let mut writing = WRITING.lock();
+  if writing.get(&key) == Some(&fill) {
+    writing.remove(&key);
+  }
+}
+
+enum Kept {
+  Yes,
+  Evicted,
+  Replaced,
+}
+
+/// Adds a written file to the disk index, if it's still the newest fill of its key
+/// and hasn't been evicted since it started.
+fn keep(fill: u64, meta: Meta) -> Kept {
+  let mut disk = DISK.lock();
+  let mut writing = WRITING.lock();
+  match writing.get(&meta.key) {
+    Some(&newest) if newest == fill => {
+      writing.remove(&meta.key);
+      info!("Cached on disk: {}: {}", meta.key, meta.size);
+      disk.insert(
+        meta.key.clone(),
+        DiskEntry {
+          meta,
+          last_used: SystemTime::now(),
+          hits: 0,
+          stored: SystemTime::now(),
+        },
+      );
+      Kept::Yes
+    }
+    Some(_) => Kept::Replaced,
+    None => Kept::Evicted,
+  }
+}
+
+async fn commit(dir: &Path, part: &Path, name: &str, meta: &Meta) -> Result<()> {
+  // as random as the data's, so two fills of the same key can't mix up their halves
+  let meta_part = part.with_extension("json.part");
+  fs::write(&meta_part, serde_json::to_vec(meta)?).await?;
+  fs::rename(part, dir.join(name)).await?;
+  fs::rename(meta_part, dir.join(format!("{name}.json"))).await?;
+  Ok(())
+}
+
+/// Drops the least recently used files until the disk cache fits its budget.
+async fn trim_disk() {
+  let victims = over_budget(&mut DISK.lock(), disk_budget(conf()));
+  for key in victims {
+    remove_files(&key).await;
+  }
+}
+
+/// Takes the least recently used entries out of `disk` until the rest fit in
+/// `budget`, returning their keys.
+fn over_budget(disk: &mut HashMap<String, DiskEntry>, budget: u64) -> Vec<String> {
+  let mut total: u64 = disk.values().map(|entry| entry.meta.size).sum();
+  let mut by_age: Vec<(SystemTime, String)> = disk
+    .iter()
+    .map(|(key, entry)| (entry.last_used, key.clone()))
+    .collect();
+  by_age.sort();
+
+  let mut victims = vec![];
+  for (_, key) in by_age {
+    if total <= budget {
+      break;
+    }
+    if let Some(entry) = disk.remove(&key) {
+      total -= entry.meta.size;
+      victims.push(key);
+    }
+  }
+  victims
+}
+
+async fn remove_files(key: &str) {
+  let name = file_name(key);
+  let _ = fs::remove_file(dir().join(&name)).await;
+  let _ = fs::remove_file(dir().join(format!("{name}.json"))).await;
+}
+
+/// Rebuilds the disk index from the cache directory, clearing out anything a crash
+/// left half written.
+pub async fn load() -> Result<()> {
+  let disk = scan(dir()).await?;
+  info!("{} files in the disk cache.", disk.len());
+  *DISK.lock() = disk;
+  trim_disk().await;
+  Ok(())
+}
+
+/// What's complete in `dir`, by key. Everything else in it gets deleted.
+async fn scan(dir: &Path) -> Result<HashMap<String, DiskEntry>> {
+  fs::create_dir_all(dir).await?;
+
+  let mut metas = HashMap::new();
+  let mut entries = fs::read_dir(dir).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    let path = entry.path();
+    let name = entry.file_name().to_string_lossy().into_owned();
+    if name.ends_with(".part") {
+      let _ = fs::remove_file(&path).await;
+    } else if let Some(name) = name.strip_suffix(".json") {
+      match fs::read(&path)
+        .await
+        .ok()
+        .and_then(|json| serde_json::from_slice::<Meta>(&json).ok())
+      {
+        Some(meta) => {
+          metas.insert(name.to_owned(), meta);
+        }
+        None => {
+          let _ = fs::remove_file(&path).await;
+        }
+      }
+    }
+  }
+
+  let mut disk = HashMap::new();
+  let mut entries = fs::read_dir(dir).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    let name = entry.file_name().to_string_lossy().into_owned();
+    if name.ends_with(".json") {
+      continue;
+    }
+    let file = entry.metadata().await?;
+    match metas.remove(&name) {
+      Some(meta) if meta.size == file.len() => {
+        let modified = file.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        disk.insert(
+          meta.key.clone(),
+          DiskEntry {
+            meta,
+            last_used: modified,
+            hits: 0,
+            stored: modified,
+          },
+        );
+      }
+      // a file without its metadata, or not the size it says, is no use
+      stale => {
+        let _ = fs::remove_file(entry.path()).await;
+        if stale.is_some() {
+          let _ = fs::remove_file(dir.join(format!("{name}.json"))).await;
+        }
+      }
+    }
+  }
+  for name in metas.keys() {
+    let _ = fs::remove_file(dir.join(format!("{name}.json"))).await;
+  }
+  Ok(disk)
+}
+
+/// Whether `key` is cached anywhere, without counting it as a hit.
+pub fn contains(key: &str) -> bool {
+  MEMORY.lock().entries.contains_key(key) || DISK.lock().contains_key(key)
+}
+
+#[derive(Serialize)]
+pub struct Listing {
+  pub key: String,
+  /// `memory`, `disk` or both.
+  pub tiers: Vec<&'static str>,
+  pub size: u64,
+  pub hits: u64,
+  /// Seconds since it was cached.
+  pub age: u64,
+}
+
+/// Everything cached, most hit first.
+pub fn list() -> Vec<Listing> {
+  let age = |stored: SystemTime| stored.elapsed().map(|d| d.as_secs()).unwrap_or_default();
+  let mut listings: HashMap<String, Listing> = HashMap::new();
+
+  for (key, entry) in DISK.lock().iter() {
+    listings.insert(
+      key.clone(),
+      Listing {
+        key: key.clone(),
+        tiers: vec!["disk"],
+        size: entry.meta.size,
+        hits: entry.hits,
+        age: age(entry.stored),
+      },
+    );
+  }
+  for (key, (memory, _)) in MEMORY.lock().entries.iter() {
+    let listing = listings.entry(key.clone()).or_insert_with(|| Listing {
+      key: key.clone(),
+      tiers: vec![],
+      size: memory.body.len() as u64,
+      hits: 0,
+      age: age(memory.stored),
+    });
+    listing.tiers.insert(0, "memory");
+    listing.hits += memory.hits;
+  }
+
+  let mut listings: Vec<Listing> = listings.into_values().collect();
+  listings.sort_by_key(|listing| Reverse(listing.hits));
+  listings
+}
+
+pub async fn evict(key: &str) {
+  MEMORY.lock().remove(key);
+
+  let on_disk = {
+    let mut disk = DISK.lock();
+    WRITING.lock().remove(key);
+    disk.remove(key).is_some()
+  };
+  if on_disk {
+    remove_files(key).await;
+  }
+}
+
+/// Empties both tiers, returning how many files were dropped.
+pub async fn clear() -> usize {
+  let mut keys: HashSet<String> = MEMORY.lock().clear().into_iter().collect();
+
+  let on_disk: Vec<String> = {
+    let mut disk = DISK.lock();
+    WRITING.lock().clear();
+    disk.drain().map(|(key, _)| key).collect()
+  };
+  for key in &on_disk {
+    remove_files(key).await;
+  }
+  keys.extend(on_disk);
+  keys.len()
+}
+
+fn conf() -> &'static CacheConfig {
+  &config::get().cache
+}
+
+fn dir() -> &'static Path {
+  &conf().dir
+}
+
+fn disk_budget(conf: &CacheConfig) -> u64 {
+  conf.disk_budget_mb * 1_000_000
+}
+
+/// Keys have slashes and whatever else in them, so files are named by their hash.
+fn file_name(key: &str) -> String {
+  Sha256::digest(key.as_bytes())
+    .iter()
+    .map(|b| format!("{b:02x}"))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use tempfile::TempDir;
+
+  const MB: u64 = 1_000_000;
+
+  fn budgets(memory_budget_mb: u64, memory_object_mb: u64, disk_budget_mb: u64) -> CacheConfig {
+    CacheConfig {
+      memory_budget_mb,
+      memory_object_mb,
+      disk_budget_mb,
+      ..Default::default()
+    }
+  }
+
+  fn memory(size: u64) -> Memory {
+    Memory {
+      body: vec![0; size as usize].into(),
+      validators: Validators::default(),
+      hits: 0,
+      stored: SystemTime::now(),
+    }
+  }
+
+  fn meta(key: &str, size: u64) -> Meta {
+    Meta {
+      key: key.to_owned(),
+      size,
+      validators: Validators::default(),
+    }
+  }
+
+  /// An entry on disk last used `secs` ago.
+  fn on_disk(key: &str, size: u64, secs: u64) -> (String, DiskEntry) {
+    let entry = DiskEntry {
+      meta: meta(key, size),
+      last_used: SystemTime::now() - Duration::from_secs(secs),
+      hits: 0,
+      stored: SystemTime::now(),
+    };
+    (key.to_owned(), entry)
+  }
+
+  fn keys(lru: &Lru) -> Vec<&str> {
+    let mut keys: Vec<&str> = lru.entries.keys().map(String::as_str).collect();
+    keys.sort();
+    keys
+  }
+
+  #[test]
+  fn memory_drops_the_least_recently_used() {
+    let conf = budgets(2, 1, 0);
+    let mut lru = Lru::default();
+    lru.insert("a", memory(800_000), &conf);
+    lru.insert("b", memory(800_000), &conf);
+    // now b is the oldest
+    assert!(lru.get("a").is_some());
+    lru.insert("c", memory(800_000), &conf);
+    assert_eq!(keys(&lru), ["a", "c"]);
+    assert_eq!(lru.bytes, 1_600_000);
+
+    // replacing a file doesn't count it twice
+    lru.insert("a", memory(300_000), &conf);
+    assert_eq!(keys(&lru), ["a", "c"]);
+    assert_eq!(lru.bytes, 1_100_000);
+
+    assert!(lru.remove("c").is_some());
+    assert!(lru.remove("c").is_none());
+    assert_eq!((lru.bytes, lru.order.len()), (300_000, 1));
+    assert_eq!(lru.clear(), ["a"]);
+    assert_eq!((lru.bytes, lru.order.len()), (0, 0));
+  }
+
+  #[test]
+  fn big_files_only_go_on_disk() {
+    let conf = budgets(64, 5, 2_000);
+    assert!(fits_in_memory(&conf, 5 * MB) && fits_on_disk(&conf, 5 * MB));
+    assert!(!fits_in_memory(&conf, 5 * MB + 1) && fits_on_disk(&conf, 5 * MB + 1));
+    assert!(!fits_on_disk(&conf, 2_000 * MB + 1));
+
+    let mut lru = Lru::default();
+    lru.insert("big", memory(5 * MB + 1), &conf);
+    assert!(lru.entries.is_empty());
+    assert_eq!(lru.bytes, 0);
+
+    // the memory budget limits objects too
+    assert!(!fits_in_memory(&budgets(1, 5, 0), 2 * MB));
+  }
+
+  #[test]
+  fn trims_the_disk_to_its_budget() {
+    let mut disk = HashMap::from([
+      on_disk("old", MB, 30),
+      on_disk("mid", MB, 20),
+      on_disk("new", MB, 10),
+    ]);
+    assert!(over_budget(&mut disk, 3 * MB).is_empty());
+    assert_eq!(over_budget(&mut disk, 2 * MB), ["old"]);
+    assert_eq!(over_budget(&mut disk, 0), ["mid", "new"]);
+    assert!(disk.is_empty());
+
+    // nothing is written to a disk cache that's turned off
+    let off = budgets(64, 5, 0);
+    assert!(!fits_on_disk(&off, 0));
+    assert!(!fits_on_disk(&off, MB));
+  }
+
+  #[tokio::test]
+  async fn loading_drops_anything_incomplete() {
+    let dir = TempDir::new().unwrap();
+    let write = |name: &str, body: &[u8]| std::fs::write(dir.path().join(name), body).unwrap();
+    let sidecar = |key: &str, size| serde_json::to_vec(&meta(key, size)).unwrap();
+
+    let whole = file_name("b/whole.png");
+    write(&whole, b"whole");
+    write(&format!("{whole}.json"), &sidecar("b/whole.png", 5));
+    // a fill cut short by a crash
+    let part = file_name("b/part.png");
+    write(&format!("{part}.1234abcd.part"), b"pa");
+    write(
+      &format!("{part}.1234abcd.json.part"),
+      &sidecar("b/part.png", 5),
+    );
+    // data without its sidecar, and the other way around
+    write(&file_name("b/lonely.png"), b"lonely");
+    write(
+      &format!("{}.json", file_name("b/ghost.png")),
+      &sidecar("b/ghost.png", 5),
+    );
+    // a sidecar that doesn't match, or doesn't parse
+    let short = file_name("b/short.png");
+    write(&short, b"sho");
+    write(&format!("{short}.json"), &sidecar("b/short.png", 5));
+    let broken = file_name("b/broken.png");
+    write(&broken, b"broken");
+    write(&format!("{broken}.json"), b"{");
+
+    let disk = scan(dir.path()).await.unwrap();
+    assert_eq!(disk.keys().collect::<Vec<_>>(), ["b/whole.png"]);
+    assert_eq!(disk["b/whole.png"].meta.size, 5);
+
+    let mut left: Vec<String> = std::fs::read_dir(dir.path())
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+      .collect();
+    left.sort();
+    assert_eq!(left, [whole.clone(), format!("{whole}.json")]);
+  }
+
+  #[tokio::test]
+  async fn evicts_and_clears() {
+    // one test, as these share the caches everything else uses
+    let dir = TempDir::new().unwrap();
+    let write = |key: &str| {
+      let (tx, rx) = mpsc::unbounded_channel();
+      let fill = start_writing(key);
+      let task = tokio::spawn(write_to_disk(dir.path().to_owned(), fill, meta(key, 4), rx));
+      (tx, task)
+    };
+
+    let (tx, task) = write("test/kept");
+    tx.send(Fill::Chunk(Bytes::from_static(b"kept"))).unwrap();
+    tx.send(Fill::Done).unwrap();
+    task.await.unwrap();
+    assert!(contains("test/kept"));
+    assert!(dir.path().join(file_name("test/kept")).exists());
+
+    // purged while it was still arriving
+    let (tx, task) = write("test/purged");
+    tx.send(Fill::Chunk(Bytes::from_static(b"purg"))).unwrap();
+    tokio::task::yield_now().await;
+    evict("test/purged").await;
+    tx.send(Fill::Done).unwrap();
+    task.await.unwrap();
+    assert!(!contains("test/purged"));
+    assert!(!dir.path().join(file_name("test/purged")).exists());
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+    remember("test/a", Bytes::from_static(b"a"), Validators::default());
+    remember("test/b", Bytes::from_static(b"b"), Validators::default());
+    let (key, entry) = on_disk("test/c", 1, 0);
+    DISK.lock().insert(key, entry);
+    assert!(contains("test/a") && contains("test/b") && contains("test/c"));
+
+    evict("test/a").await;
+    assert!(!contains("test/a"));
+    assert!(contains("test/b") && contains("test/c"));
+
+    assert!(clear().await >= 3);
+    assert!(!contains("test/b") && !contains("test/c") && !contains("test/kept"));
+  }
+}
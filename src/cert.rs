@@ -1,5 +1,21 @@
 use acme_lib::{create_p384_key, persist::FilePersist, Directory, DirectoryUrl};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use std::{
+  io::ErrorKind,
+  time::{SystemTime, UNIX_EPOCH},
+};
+use x509_parser::pem::parse_x509_pem;
+
+/// The account `FilePersist` keeps our certificate and key under.
+const LETS_ENCRYPT_ACCOUNT: &str = "17287977548916597336";
+
+pub fn cert_file() -> String {
+  format!("{LETS_ENCRYPT_ACCOUNT}_crt_kota_is.crt")
+}
+
+pub fn key_file() -> String {
+  format!("{LETS_ENCRYPT_ACCOUNT}_key_kota_is.key")
+}
 
 pub fn request_cert() -> Result<()> {
   let url = DirectoryUrl::LetsEncrypt;
@@ -64,3 +80,21 @@ pub fn request_cert() -> Result<()> {
 
   std::process::exit(0);
 }
+
+/// Days until the kota.is certificate expires, if we have one at all. Read off the
+/// one on disk, so checking never talks to Let's Encrypt.
+pub fn days_left() -> Result<Option<i64>> {
+  let pem = match std::fs::read(cert_file()) {
+    Ok(pem) => pem,
+    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e.into()),
+  };
+  let (_, pem) = parse_x509_pem(&pem).map_err(|e| anyhow!("Unreadable certificate: {e}"))?;
+  let cert = pem
+    .parse_x509()
+    .map_err(|e| anyhow!("Unreadable certificate: {e}"))?;
+
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+  let left = cert.validity().not_after.timestamp() - now;
+  Ok(Some(left.div_euclid(86_400)))
+}
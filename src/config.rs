@@ -0,0 +1,368 @@
+use anyhow::{Context, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use std::{
+  path::{Path, PathBuf},
+  sync::OnceLock,
+  time::Duration,
+};
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// What gets escaped in each segment of a key when it goes in a url.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+  .remove(b'-')
+  .remove(b'.')
+  .remove(b'_')
+  .remove(b'~');
+
+/// Settings read once at startup from `smee.toml` (or `--config`).
+/// Every field has a default, so the file is optional.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Config {
+  pub download: DownloadConfig,
+  pub ids: IdConfig,
+  pub http: HttpConfig,
+  pub cache: CacheConfig,
+}
+
+/// Where the proxy keeps files it has fetched, see `cache`.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct CacheConfig {
+  /// Total size of files kept in memory.
+  pub memory_budget_mb: u64,
+  /// Anything bigger is only cached on disk.
+  pub memory_object_mb: u64,
+  pub dir: PathBuf,
+  /// Least recently used files are deleted past this. 0 turns the disk cache off.
+  pub disk_budget_mb: u64,
+}
+
+impl Default for CacheConfig {
+  fn default() -> Self {
+    Self {
+      memory_budget_mb: 64,
+      memory_object_mb: 5,
+      dir: PathBuf::from("cache"),
+      disk_budget_mb: 2_000,
+    }
+  }
+}
+
+/// How the file proxy answers, see `http`.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct HttpConfig {
+  /// Giving any replaces the default `/` and `/v/` routes.
+  pub routes: Vec<ProxyRoute>,
+  /// Host in links to hosted files, for routes that answer any host.
+  pub public_host: String,
+}
+
+/// Which bucket a request's host and path are served from.
+///
+/// ```toml
+/// [[http.routes]]
+/// hosts = ["pics.example.com"]
+/// prefix = "/trip/"
+/// bucket = "my-pics"
+/// rewrite = "2024/trip/"
+/// cache_control = "public, max-age=86400"
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ProxyRoute {
+  /// Host headers answered, without the port. Any host when empty.
+  pub hosts: Vec<String>,
+  /// The rest of the path after this is the key. The longest matching prefix wins.
+  pub prefix: String,
+  /// Base url of the storage the bucket is on.
+  pub origin: String,
+  pub bucket: String,
+  /// Put in front of keys in place of the prefix.
+  pub rewrite: String,
+  /// Links with a ttl are never called fresh for longer than they'll be around.
+  pub cache_control: Option<String>,
+  /// Answer paths ending in `/` with a gallery of what's under them.
+  pub index: bool,
+}
+
+impl Default for ProxyRoute {
+  fn default() -> Self {
+    Self {
+      hosts: Vec::new(),
+      prefix: "/".to_owned(),
+      origin: "https://f001.backblazeb2.com/file".to_owned(),
+      bucket: String::new(),
+      rewrite: String::new(),
+      cache_control: None,
+      index: false,
+    }
+  }
+}
+
+impl Default for HttpConfig {
+  fn default() -> Self {
+    // ids are never reused, not even after their file is deleted (see `index::bury`),
+    // so what's behind one never changes
+    let forever = Some("public, max-age=31536000, immutable".to_owned());
+    Self {
+      routes: vec![
+        ProxyRoute {
+          bucket: "i-kota".to_owned(),
+          cache_control: forever.clone(),
+          ..Default::default()
+        },
+        ProxyRoute {
+          prefix: "/v/".to_owned(),
+          bucket: "v-kota".to_owned(),
+          cache_control: forever,
+          ..Default::default()
+        },
+      ],
+      public_host: "kota.is".to_owned(),
+    }
+  }
+}
+
+impl HttpConfig {
+  /// The route for a request and the rest of its path after the prefix. Without a
+  /// host, only routes for any host match.
+  pub fn route_for<'a>(&self, host: Option<&str>, path: &'a str) -> Option<(&ProxyRoute, &'a str)> {
+    let host = host.map(|host| match host.rsplit_once(':') {
+      Some((name, port)) if port.parse::<u16>().is_ok() => name,
+      _ => host,
+    });
+    self
+      .routes
+      .iter()
+      .filter(|route| {
+        route.hosts.is_empty()
+          || host.is_some_and(|host| route.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+      })
+      .filter_map(|route| Some((route, path.strip_prefix(&route.prefix)?)))
+      .max_by_key(|(route, _)| route.prefix.len())
+  }
+
+  /// A route serving `bucket`, for fetching from it by key.
+  pub fn route_to(&self, bucket: &str) -> Option<&ProxyRoute> {
+    self.routes.iter().find(|route| route.bucket == bucket)
+  }
+
+  /// The public link to `key` in `bucket`, through the route serving it.
+  pub fn link(&self, bucket: &str, key: &str) -> Option<String> {
+    self.routes.iter().find_map(|route| {
+      let rest = key.strip_prefix(&route.rewrite)?;
+      let host = route.hosts.first().unwrap_or(&self.public_host);
+      (route.bucket == bucket)
+        .then(|| format!("https://{host}{}{}", route.prefix, encode_path(rest)))
+    })
+  }
+}
+
+impl ProxyRoute {
+  /// The key for a decoded path after the prefix.
+  pub fn key(&self, rest: &str) -> String {
+    format!("{}{rest}", self.rewrite)
+  }
+
+  pub fn url(&self, key: &str) -> String {
+    format!(
+      "{}/{}/{}",
+      self.origin.trim_end_matches('/'),
+      self.bucket,
+      encode_path(key)
+    )
+  }
+}
+
+/// Escapes each segment of `path` for use in a url, leaving the slashes.
+pub fn encode_path(path: &str) -> String {
+  let segments: Vec<String> = path
+    .split('/')
+    .map(|segment| utf8_percent_encode(segment, SEGMENT).to_string())
+    .collect();
+  segments.join("/")
+}
+
+/// How public keys for hosted files are picked, see `ids`.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct IdConfig {
+  /// Characters in a fresh id. Ids grow when they collide.
+  pub length: usize,
+  pub alphabet: String,
+  /// Derive ids from a hash of the contents, so the same file keeps the same link.
+  pub content_hash: bool,
+  /// HEAD the bucket before using an id, not just the local index.
+  pub check_storage: bool,
+}
+
+impl Default for IdConfig {
+  fn default() -> Self {
+    Self {
+      length: 5,
+      alphabet: "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789".to_owned(),
+      content_hash: false,
+      check_storage: true,
+    }
+  }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct DownloadConfig {
+  /// Total tries per download, including the first one.
+  pub attempts: u32,
+  /// Delay before the first retry, doubled for every retry after that.
+  pub backoff_ms: u64,
+  /// Passed as `--extractor-args` on the alternate extractor fallback.
+  pub extractor_args: String,
+  /// Network settings for particular sites, see `Route`.
+  pub routes: Vec<Route>,
+}
+
+/// How downloads from some domains reach the internet.
+///
+/// ```toml
+/// [[download.routes]]
+/// name = "tunnel"
+/// domains = ["bbc.co.uk"]
+/// proxy = "socks5://127.0.0.1:1080"
+/// rate_limit = "5M"
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Route {
+  /// What `via=<name>` refers to.
+  pub name: String,
+  /// Matched against the url's host and its parent domains.
+  pub domains: Vec<String>,
+  /// Anything yt-dlp's `--proxy` takes, http(s):// or socks5://.
+  pub proxy: Option<String>,
+  /// Local IP to bind to.
+  pub source_address: Option<String>,
+  /// Bytes per second, like `50K` or `4.2M`.
+  pub rate_limit: Option<String>,
+  pub user_agent: Option<String>,
+}
+
+impl Default for DownloadConfig {
+  fn default() -> Self {
+    Self {
+      // the first try, then one for each `Fallback`
+      attempts: 5,
+      backoff_ms: 2_000,
+      extractor_args: "youtube:player_client=android,web".to_owned(),
+      routes: Vec::new(),
+    }
+  }
+}
+
+impl DownloadConfig {
+  pub fn route(&self, name: &str) -> Option<&Route> {
+    self.routes.iter().find(|route| route.name == name)
+  }
+
+  /// The route whose domains match `url`, if any.
+  pub fn route_for(&self, url: &str) -> Option<&Route> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    self.routes.iter().find(|route| {
+      route
+        .domains
+        .iter()
+        .any(|domain| host == domain || host.ends_with(&format!(".{domain}")))
+    })
+  }
+
+  /// How long to wait after failed attempt number `attempt` (starting at 1).
+  pub fn backoff(&self, attempt: u32) -> Duration {
+    Duration::from_millis(
+      self
+        .backoff_ms
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16)),
+    )
+  }
+}
+
+pub fn load(path: &Path) -> Result<()> {
+  let config = match std::fs::read_to_string(path) {
+    Ok(toml) => toml::from_str(&toml).with_context(|| format!("Invalid {}", path.display()))?,
+    Err(_) => {
+      info!("No {} found, using defaults.", path.display());
+      Config::default()
+    }
+  };
+
+  let _ = CONFIG.set(config);
+  Ok(())
+}
+
+pub fn get() -> &'static Config {
+  CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn http() -> HttpConfig {
+    let mut http = HttpConfig::default();
+    http.routes.push(ProxyRoute {
+      hosts: vec!["pics.example.com".to_owned()],
+      prefix: "/trip/".to_owned(),
+      bucket: "my-pics".to_owned(),
+      rewrite: "2024/trip/".to_owned(),
+      ..Default::default()
+    });
+    http
+  }
+
+  #[test]
+  fn hosts_pick_routes() {
+    let http = http();
+    let bucket = |host, path| {
+      http
+        .route_for(host, path)
+        .map(|(route, rest)| (route.bucket.as_str(), rest))
+    };
+
+    assert_eq!(
+      bucket(Some("kota.is"), "/v/a.mp4"),
+      Some(("v-kota", "a.mp4"))
+    );
+    assert_eq!(
+      bucket(Some("kota.is"), "/trip/a.png"),
+      Some(("i-kota", "trip/a.png"))
+    );
+    assert_eq!(
+      bucket(Some("PICS.example.com:443"), "/trip/a.png"),
+      Some(("my-pics", "a.png"))
+    );
+    // no host only gets the routes for any host
+    assert_eq!(bucket(None, "/trip/a.png"), Some(("i-kota", "trip/a.png")));
+  }
+
+  #[test]
+  fn links_go_through_routes() {
+    let http = http();
+
+    assert_eq!(
+      http.link("i-kota", "a b.png").as_deref(),
+      Some("https://kota.is/a%20b.png")
+    );
+    assert_eq!(
+      http.link("v-kota", "a.mp4").as_deref(),
+      Some("https://kota.is/v/a.mp4")
+    );
+    assert_eq!(
+      http.link("my-pics", "2024/trip/a.png").as_deref(),
+      Some("https://pics.example.com/trip/a.png")
+    );
+    assert_eq!(http.link("my-pics", "2023/a.png"), None);
+    assert_eq!(http.link("elsewhere", "a.png"), None);
+  }
+}
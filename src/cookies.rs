@@ -0,0 +1,200 @@
+use anyhow::{bail, Result};
+use std::{
+  io::ErrorKind,
+  path::{Path, PathBuf},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+/// One Netscape-format cookie file per domain, named `<domain>.txt`.
+const COOKIE_DIR: &str = "cookies";
+/// The old single cookie file, used for sites without a jar of their own.
+const LEGACY_COOKIES: &str = "cookies.txt";
+
+/// What we know about a cookie file without handing it to yt-dlp.
+pub struct Jar {
+  pub cookies: usize,
+  pub expired: usize,
+  /// Earliest expiry of the cookies that haven't expired yet, in unix seconds.
+  /// Session cookies don't count.
+  pub expires: Option<u64>,
+}
+
+impl Jar {
+  pub fn parse(contents: &str) -> Result<Self> {
+    let now = now();
+    let mut jar = Jar {
+      cookies: 0,
+      expired: 0,
+      expires: None,
+    };
+
+    for line in contents.lines() {
+      // curl marks httponly cookies with this prefix, everything else starting with # is a comment
+      let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+      if line.trim().is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      let fields: Vec<&str> = line.split('\t').collect();
+      let [_, _, _, _, expiry, _, _] = fields[..] else {
+        bail!("Not a Netscape cookie file, expected 7 tab separated fields: {line}");
+      };
+      let Ok(expiry) = expiry.parse::<u64>() else {
+        bail!("Bad expiry in cookie file: {expiry}");
+      };
+
+      jar.cookies += 1;
+      match expiry {
+        0 => {}
+        expiry if expiry <= now => jar.expired += 1,
+        expiry => jar.expires = Some(jar.expires.map_or(expiry, |e| e.min(expiry))),
+      }
+    }
+
+    if jar.cookies == 0 {
+      bail!("No cookies in that file.");
+    }
+    Ok(jar)
+  }
+
+  /// Nothing in here will get us logged in anymore.
+  pub fn is_stale(&self) -> bool {
+    self.expired == self.cookies
+  }
+
+  pub fn describe(&self) -> String {
+    let expiry = match self.expires {
+      _ if self.is_stale() => "all expired".to_owned(),
+      Some(expires) => format!(
+        "next expiry in {} days",
+        expires.saturating_sub(now()) / 86_400
+      ),
+      None => "session only".to_owned(),
+    };
+    format!(
+      "{} cookies, {} expired, {expiry}",
+      self.cookies, self.expired
+    )
+  }
+}
+
+/// Lowercases and strips what people tend to paste along with a domain.
+pub fn normalize_domain(domain: &str) -> Result<String> {
+  let domain = domain
+    .trim()
+    .trim_start_matches("https://")
+    .trim_start_matches("http://")
+    .trim_start_matches('.')
+    .trim_start_matches("www.")
+    .trim_end_matches('/')
+    .to_lowercase();
+
+  let valid = domain.contains('.')
+    && domain
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+  if !valid {
+    bail!("{domain} doesn't look like a domain.");
+  }
+  Ok(domain)
+}
+
+fn jar_path(domain: &str) -> PathBuf {
+  Path::new(COOKIE_DIR).join(format!("{domain}.txt"))
+}
+
+/// Validates and stores a cookie file for `domain`, replacing any previous one.
+pub fn save(domain: &str, contents: &[u8]) -> Result<Jar> {
+  let domain = normalize_domain(domain)?;
+  let jar = Jar::parse(&String::from_utf8_lossy(contents))?;
+
+  std::fs::create_dir_all(COOKIE_DIR)?;
+  let path = jar_path(&domain);
+  let tmp = path.with_extension("txt.tmp");
+  std::fs::write(&tmp, contents)?;
+  std::fs::rename(tmp, path)?;
+
+  Ok(jar)
+}
+
+/// Deletes the jar for `domain`, returning whether there was one.
+pub fn remove(domain: &str) -> Result<bool> {
+  match std::fs::remove_file(jar_path(&normalize_domain(domain)?)) {
+    Ok(()) => Ok(true),
+    Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+    Err(err) => Err(err.into()),
+  }
+}
+
+/// Every stored jar and its state, for `/cookies`.
+pub fn summary() -> String {
+  let Ok(entries) = std::fs::read_dir(COOKIE_DIR) else {
+    return "No cookie jars.".to_owned();
+  };
+
+  let mut lines: Vec<String> = entries
+    .flatten()
+    .filter_map(|entry| {
+      let path = entry.path();
+      let domain = path.file_name()?.to_str()?.strip_suffix(".txt")?.to_owned();
+      let state = match std::fs::read_to_string(&path).map_err(anyhow::Error::from) {
+        Ok(contents) => Jar::parse(&contents).map(|jar| jar.describe()),
+        Err(err) => Err(err),
+      };
+      Some(match state {
+        Ok(state) => format!("{domain}: {state}"),
+        Err(err) => format!("{domain}: unreadable ({err})"),
+      })
+    })
+    .collect();
+
+  if lines.is_empty() {
+    return "No cookie jars.".to_owned();
+  }
+  lines.sort();
+  lines.join("\n")
+}
+
+/// The cookie file to use for `url`: the jar for the most specific domain matching
+/// its host, falling back to the legacy `cookies.txt`. Stale jars are skipped.
+pub fn for_url(url: &str) -> Option<PathBuf> {
+  let host = reqwest::Url::parse(url).ok()?.host_str()?.to_lowercase();
+
+  // foo.bar.example.com, bar.example.com, example.com
+  let mut candidates = vec![host.as_str()];
+  candidates.extend(host.match_indices('.').map(|(i, _)| &host[i + 1..]));
+
+  let jar = candidates
+    .into_iter()
+    .filter(|domain| domain.contains('.'))
+    .map(jar_path)
+    .find(|path| match std::fs::read_to_string(path) {
+      Ok(contents) => match Jar::parse(&contents) {
+        Ok(jar) if jar.is_stale() => {
+          warn!(
+            "Cookies in {} have all expired, not using them.",
+            path.display()
+          );
+          false
+        }
+        Ok(_) => true,
+        Err(err) => {
+          warn!("Ignoring {}: {err}", path.display());
+          false
+        }
+      },
+      Err(_) => false,
+    });
+
+  jar.or_else(|| {
+    let legacy = PathBuf::from(LEGACY_COOKIES);
+    legacy.exists().then_some(legacy)
+  })
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or_default()
+}
@@ -0,0 +1,217 @@
+mod direct;
+mod fake;
+mod gallery_dl;
+mod ytdlp;
+
+use crate::config::Route;
+use crate::error::DownloadError;
+use crate::settings::ChatSettings;
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+
+/// Something that can turn a url into files on disk.
+#[async_trait]
+pub trait Downloader: Send + Sync {
+  fn name(&self) -> &'static str;
+
+  async fn download(&self, request: &Request<'_>) -> Result<Vec<File>, DownloadError>;
+}
+
+/// Everything a backend needs to know about one download attempt.
+/// Backends may ignore options that don't apply to them.
+pub struct Request<'a> {
+  pub url: &'a str,
+  /// Only keep the audio, if the backend can tell the difference.
+  pub audio_only: bool,
+  pub size_limit_mb: u32,
+  /// Container, resolution and audio codec preferences.
+  pub settings: &'a ChatSettings,
+  pub route: Option<&'a Route>,
+  pub fallback: Fallback,
+  /// Files must be written to this path, plus whatever extension they need.
+  /// Backends producing several files may make it a directory instead.
+  pub out: &'a Path,
+}
+
+impl Request<'_> {
+  fn size_limit(&self) -> u64 {
+    self.size_limit_mb as u64 * 1_000_000
+  }
+}
+
+/// A downloaded file and what we know about it.
+pub struct File {
+  pub path: PathBuf,
+  pub title: Option<String>,
+}
+
+impl File {
+  pub fn mime(&self) -> mime_guess::Mime {
+    mime_guess::from_path(&self.path).first_or_octet_stream()
+  }
+}
+
+#[cfg(test)]
+pub use fake::fixtures as fake_fixtures;
+
+/// What to do differently when retrying a download.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fallback {
+  /// Same thing again, for network hiccups.
+  Retry,
+  /// Stale cookies get us blocked more often than they help.
+  WithoutCookies,
+  /// Smaller formats are less likely to trip size limits and throttling.
+  LowerQuality,
+  /// Some extractors have other clients that work when the default one breaks.
+  AlternateExtractor,
+}
+
+impl Fallback {
+  const ORDER: [Self; 4] = [
+    Self::Retry,
+    Self::WithoutCookies,
+    Self::LowerQuality,
+    Self::AlternateExtractor,
+  ];
+
+  /// The first attempt and the first retry are plain, the fallbacks come after that.
+  pub fn for_attempt(attempt: u32) -> Self {
+    let i = (attempt.saturating_sub(2) as usize).min(Self::ORDER.len() - 1);
+    Self::ORDER[i]
+  }
+
+  pub fn message_key(&self) -> &'static str {
+    match self {
+      Self::Retry => "fallback_retry",
+      Self::WithoutCookies => "fallback_no_cookies",
+      Self::LowerQuality => "fallback_lower_quality",
+      Self::AlternateExtractor => "fallback_alt_extractor",
+    }
+  }
+}
+
+/// File types that get fetched as they are instead of going through an extractor.
+pub(super) const DIRECT_EXTENSIONS: &[&str] = &[
+  "mp4", "webm", "mkv", "mov", "mp3", "m4a", "ogg", "opus", "flac", "wav", "jpg", "jpeg", "png",
+  "gif", "webp", "avif",
+];
+
+/// Sites that are mostly pictures, which yt-dlp doesn't do. Matched like
+/// `AUTO_MIRROR_RULES`: the domain and its subdomains, optionally only under a path prefix.
+const GALLERY_RULES: &[(&str, Option<&str>)] = &[
+  ("imgur.com", Some("/a/")),
+  ("imgur.com", Some("/gallery/")),
+  ("instagram.com", Some("/p/")),
+  ("pixiv.net", None),
+  ("flickr.com", None),
+  ("deviantart.com", None),
+  ("artstation.com", None),
+  ("danbooru.donmai.us", None),
+  ("bsky.app", None),
+];
+
+/// Picks the backend for `url`. yt-dlp handles anything nobody else claims.
+pub fn for_url(url: &str) -> &'static dyn Downloader {
+  let Ok(url) = reqwest::Url::parse(url) else {
+    return &ytdlp::YtDlp;
+  };
+
+  if let Some(backend) = fixed_backend(&url) {
+    return backend;
+  }
+  if is_gallery(&url) {
+    return &gallery_dl::GalleryDl;
+  }
+
+  &ytdlp::YtDlp
+}
+
+/// Like `for_url`, but for when we know we're after pictures, which yt-dlp doesn't do.
+pub fn for_images(url: &str) -> &'static dyn Downloader {
+  reqwest::Url::parse(url)
+    .ok()
+    .and_then(|url| fixed_backend(&url))
+    .unwrap_or(&gallery_dl::GalleryDl)
+}
+
+/// Urls only one backend makes sense for, whatever we're after.
+fn fixed_backend(url: &reqwest::Url) -> Option<&'static dyn Downloader> {
+  if url.scheme() == fake::SCHEME && fake::enabled() {
+    return Some(&fake::Fake);
+  }
+
+  let extension = Path::new(url.path())
+    .extension()
+    .and_then(|ext| ext.to_str())
+    .map(|ext| ext.to_lowercase());
+  if extension.is_some_and(|ext| DIRECT_EXTENSIONS.contains(&ext.as_str())) {
+    return Some(&direct::Direct);
+  }
+
+  None
+}
+
+/// Whether `url` is on a site that's mostly pictures, see `GALLERY_RULES`.
+pub fn is_gallery(url: &reqwest::Url) -> bool {
+  let host = url.host_str().unwrap_or_default();
+  GALLERY_RULES.iter().any(|(domain, path)| {
+    (host == *domain || host.ends_with(&format!(".{domain}")))
+      && path.is_none_or(|path| url.path().starts_with(path))
+  })
+}
+
+/// The route's network options as command line flags. yt-dlp and gallery-dl agree on these.
+fn route_args(route: &Route) -> Vec<String> {
+  let flags = [
+    ("--proxy", &route.proxy),
+    ("--source-address", &route.source_address),
+    ("--limit-rate", &route.rate_limit),
+    ("--user-agent", &route.user_agent),
+  ];
+
+  flags
+    .into_iter()
+    .filter_map(|(flag, value)| Some([flag.to_owned(), value.clone()?]))
+    .flatten()
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn picks_a_backend_for_each_url() {
+    fake::fixtures();
+    let backend = |url| for_url(url).name();
+    assert_eq!(backend("fixture://clip.mp4"), "fake");
+    assert_eq!(backend("https://example.com/files/clip.MP4"), "direct");
+    assert_eq!(backend("https://imgur.com/a/abc"), "gallery-dl");
+    assert_eq!(backend("https://i.imgur.com/gallery/abc"), "gallery-dl");
+    assert_eq!(backend("https://imgur.com/abc"), "yt-dlp");
+    assert_eq!(backend("https://www.youtube.com/watch?v=abc"), "yt-dlp");
+    assert_eq!(backend("not a url"), "yt-dlp");
+  }
+
+  #[test]
+  fn default_attempts_reach_every_fallback() {
+    let attempts = crate::config::DownloadConfig::default().attempts;
+    let tried: Vec<Fallback> = (1..=attempts).map(Fallback::for_attempt).collect();
+    assert_eq!(tried[..2], [Fallback::Retry, Fallback::Retry]);
+    for fallback in Fallback::ORDER {
+      assert!(tried.contains(&fallback), "{fallback:?} is never tried");
+    }
+  }
+
+  #[test]
+  fn pictures_never_go_to_yt_dlp() {
+    fake::fixtures();
+    assert_eq!(for_images("fixture://album").name(), "fake");
+    assert_eq!(for_images("https://example.com/cat.png").name(), "direct");
+    assert_eq!(
+      for_images("https://example.com/post/1").name(),
+      "gallery-dl"
+    );
+  }
+}
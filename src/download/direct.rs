@@ -0,0 +1,196 @@
+use super::{ytdlp::YtDlp, Downloader, File, Request, DIRECT_EXTENSIONS};
+use crate::error::DownloadError;
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::{net::IpAddr, path::Path, time::Duration};
+use tokio::io::AsyncWriteExt;
+
+/// Plain links to files, fetched as they are.
+pub struct Direct;
+
+#[async_trait]
+impl Downloader for Direct {
+  fn name(&self) -> &'static str {
+    "direct"
+  }
+
+  async fn download(&self, request: &Request<'_>) -> Result<Vec<File>, DownloadError> {
+    // yt-dlp takes plain links too, and can pull the audio out of a video
+    if request.audio_only && !is_audio(request.url) {
+      return YtDlp.download(request).await;
+    }
+
+    let fail = |err: reqwest::Error| DownloadError::Downloader(err.to_string());
+
+    let response = client(request)?
+      .get(request.url)
+      .send()
+      .await
+      .and_then(|r| r.error_for_status())
+      .map_err(fail)?;
+
+    let too_large = || {
+      DownloadError::TooLarge(format!(
+        "{} is over {}MB",
+        request.url, request.size_limit_mb
+      ))
+    };
+    if response
+      .content_length()
+      .is_some_and(|len| len > request.size_limit())
+    {
+      return Err(too_large());
+    }
+
+    let name = Path::new(response.url().path())
+      .file_name()
+      .map(|name| name.to_string_lossy().into_owned());
+    let content_type = response
+      .headers()
+      .get(reqwest::header::CONTENT_TYPE)
+      .and_then(|value| value.to_str().ok());
+    let path = request
+      .out
+      .with_extension(extension(name.as_deref(), content_type));
+
+    // content-length can lie or be missing, so count as we go
+    let mut file = tokio::fs::File::create(&path).await?;
+    let mut written = 0;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+      let chunk = chunk.map_err(fail)?;
+      written += chunk.len() as u64;
+      if written > request.size_limit() {
+        return Err(too_large());
+      }
+      file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    Ok(vec![File { path, title: name }])
+  }
+}
+
+/// The extension to save a download under. It ends up in a hosted file's key, so it's
+/// only taken from the name when it's short, plain and agrees with the `Content-Type`.
+fn extension(name: Option<&str>, content_type: Option<&str>) -> String {
+  let named = name
+    .and_then(|name| Path::new(name).extension()?.to_str())
+    .map(str::to_ascii_lowercase)
+    .filter(|ext| (1..=8).contains(&ext.len()) && ext.chars().all(|c| c.is_ascii_alphanumeric()));
+  let essence = content_type
+    .and_then(|content_type| content_type.split(';').next())
+    .map(|essence| essence.trim().to_ascii_lowercase())
+    .filter(|essence| !essence.is_empty() && essence != "application/octet-stream");
+
+  let Some(essence) = essence else {
+    return named.unwrap_or_else(|| "bin".to_owned());
+  };
+  let agrees = |ext: &str| {
+    mime_guess::from_ext(ext)
+      .iter()
+      .any(|mime| mime.essence_str() == essence)
+  };
+  if let Some(named) = named.as_deref().filter(|ext| agrees(ext)) {
+    return named.to_owned();
+  }
+  let known = mime_guess::get_mime_extensions_str(&essence).unwrap_or_default();
+  DIRECT_EXTENSIONS
+    .iter()
+    .find(|ext| known.contains(ext))
+    .or(known.first())
+    .map(|ext| ext.to_string())
+    // a type we don't know, the name may still be right
+    .or(named)
+    .unwrap_or_else(|| "bin".to_owned())
+}
+
+fn is_audio(url: &str) -> bool {
+  let path = reqwest::Url::parse(url).map(|url| url.path().to_owned());
+  mime_guess::from_path(path.unwrap_or_default())
+    .first()
+    .is_some_and(|mime| mime.type_() == mime_guess::mime::AUDIO)
+}
+
+fn client(request: &Request) -> Result<reqwest::Client, DownloadError> {
+  let mut builder = reqwest::Client::builder().connect_timeout(Duration::from_secs(15));
+
+  if let Some(route) = request.route {
+    if let Some(proxy) = &route.proxy {
+      let proxy =
+        reqwest::Proxy::all(proxy).map_err(|err| DownloadError::Downloader(err.to_string()))?;
+      builder = builder.proxy(proxy);
+    }
+    if let Some(address) = &route.source_address {
+      let address: IpAddr = address
+        .parse()
+        .map_err(|_| DownloadError::Downloader(format!("Bad source address {address}")))?;
+      builder = builder.local_address(address);
+    }
+    if let Some(user_agent) = &route.user_agent {
+      builder = builder.user_agent(user_agent);
+    }
+    if route.rate_limit.is_some() {
+      debug!(
+        "Route {} has a rate limit, direct downloads ignore it.",
+        route.name
+      );
+    }
+  }
+
+  builder
+    .build()
+    .map_err(|err| DownloadError::Downloader(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::download::Fallback;
+  use crate::settings::ChatSettings;
+  use warp::Filter;
+
+  #[test]
+  fn extensions_are_plain_and_match_the_content() {
+    let ext = |name, content_type| extension(Some(name), content_type);
+    assert_eq!(ext("clip.MP4", Some("video/mp4")), "mp4");
+    assert_eq!(ext("clip.mp4", None), "mp4");
+    assert_eq!(ext("clip.mp4", Some("application/octet-stream")), "mp4");
+    assert_eq!(ext("x.php", Some("video/mp4; charset=binary")), "mp4");
+    assert_eq!(ext("x.php", Some("image/jpeg")), "jpg");
+    assert_eq!(ext("x.php", Some("application/x-made-up")), "php");
+    // nothing odd makes it into a key
+    assert_eq!(ext("a.m p4", None), "bin");
+    assert_eq!(ext("a.mp4%00", None), "bin");
+    assert_eq!(ext("a.averylongone", None), "bin");
+    assert_eq!(ext("a.tar.gz", None), "gz");
+    assert_eq!(ext("noext", None), "bin");
+    assert_eq!(extension(None, Some("audio/mpeg")), "mp3");
+  }
+
+  #[tokio::test]
+  async fn names_files_after_what_the_redirect_serves() {
+    let redirect = warp::path("clip.mp4")
+      .map(|| warp::redirect::found(warp::http::Uri::from_static("/x.php?id=1")));
+    let served = warp::path("x.php")
+      .map(|| warp::reply::with_header(b"fake webm".to_vec(), "content-type", "video/webm"));
+    let (addr, server) = warp::serve(redirect.or(served)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let (url, out) = (format!("http://{addr}/clip.mp4"), dir.path().join("job"));
+    let settings = ChatSettings::default();
+    let request = Request {
+      url: &url,
+      audio_only: false,
+      size_limit_mb: 1,
+      settings: &settings,
+      route: None,
+      fallback: Fallback::Retry,
+      out: &out,
+    };
+    let files = Direct.download(&request).await.unwrap();
+    assert_eq!(files[0].path, dir.path().join("job.webm"));
+    assert_eq!(std::fs::read(&files[0].path).unwrap(), b"fake webm");
+  }
+}
@@ -0,0 +1,164 @@
+use super::{Downloader, File, Request};
+use crate::error::DownloadError;
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+
+pub const SCHEME: &str = "fixture";
+/// Directory fixtures are served from. The fake backend is off unless this is set.
+const FIXTURES_VAR: &str = "SMEE_FIXTURES";
+
+/// Serves `fixture://<name>` from the fixtures directory instead of the internet,
+/// for trying out the bot without hitting real sites. A directory fixture becomes a gallery.
+/// Fixtures named after an error (`unsupported`, `geo`, `login`, `too-large`) fail like one.
+pub struct Fake;
+
+pub fn enabled() -> bool {
+  std::env::var_os(FIXTURES_VAR).is_some()
+}
+
+#[async_trait]
+impl Downloader for Fake {
+  fn name(&self) -> &'static str {
+    "fake"
+  }
+
+  async fn download(&self, request: &Request<'_>) -> Result<Vec<File>, DownloadError> {
+    let name = request
+      .url
+      .strip_prefix(&format!("{SCHEME}://"))
+      .unwrap_or_default()
+      .trim_matches('/');
+
+    match name {
+      "unsupported" => return Err(DownloadError::UnsupportedSite(name.to_owned())),
+      "geo" => return Err(DownloadError::GeoBlocked(name.to_owned())),
+      "login" => return Err(DownloadError::LoginRequired(name.to_owned())),
+      "too-large" => return Err(DownloadError::TooLarge(name.to_owned())),
+      _ => {}
+    }
+
+    let fixtures = PathBuf::from(std::env::var_os(FIXTURES_VAR).unwrap_or_default());
+    let fixture = fixtures.join(name);
+    // no climbing out of the fixtures directory
+    if name.is_empty() || name.split('/').any(|part| part == "..") || !fixture.exists() {
+      return Err(DownloadError::UnsupportedSite(format!("no fixture {name}")));
+    }
+
+    if !fixture.is_dir() {
+      return Ok(vec![copy(&fixture, request.out)?]);
+    }
+
+    std::fs::create_dir_all(request.out)?;
+    let mut files = std::fs::read_dir(&fixture)?
+      .flatten()
+      .map(|entry| copy(&entry.path(), &request.out.join(entry.file_name())))
+      .collect::<Result<Vec<_>, _>>()?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+  }
+}
+
+fn copy(fixture: &Path, to: &Path) -> Result<File, DownloadError> {
+  let path = match fixture.extension() {
+    Some(ext) => to.with_extension(ext),
+    None => to.to_path_buf(),
+  };
+  std::fs::copy(fixture, &path)?;
+
+  Ok(File {
+    path,
+    title: fixture
+      .file_stem()
+      .map(|stem| stem.to_string_lossy().into_owned()),
+  })
+}
+
+/// A fixtures directory for tests, made once and shared since the variable is process wide.
+#[cfg(test)]
+pub fn fixtures() -> &'static Path {
+  use std::sync::OnceLock;
+
+  static DIR: OnceLock<PathBuf> = OnceLock::new();
+  DIR.get_or_init(|| {
+    let dir = std::env::temp_dir().join(format!("smee-fixtures-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("album")).unwrap();
+    for (name, body) in [
+      ("clip.mp4", "video"),
+      ("song.mp3", "audio"),
+      ("album/1.png", "one"),
+      ("album/2.png", "two"),
+    ] {
+      std::fs::write(dir.join(name), body).unwrap();
+    }
+    std::env::set_var(FIXTURES_VAR, &dir);
+    dir
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::download::Fallback;
+  use crate::settings::ChatSettings;
+
+  async fn fetch(url: &str, out: &str) -> Result<Vec<File>, DownloadError> {
+    fixtures();
+    let out = std::env::temp_dir().join(format!("smee-out-{}-{out}", std::process::id()));
+    let settings = ChatSettings::default();
+    let request = Request {
+      url,
+      audio_only: false,
+      size_limit_mb: 50,
+      settings: &settings,
+      route: None,
+      fallback: Fallback::Retry,
+      out: &out,
+    };
+    Fake.download(&request).await
+  }
+
+  #[tokio::test]
+  async fn serves_a_file() {
+    let files = fetch("fixture://clip.mp4", "file").await.unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].path.extension().unwrap(), "mp4");
+    assert_eq!(files[0].title.as_deref(), Some("clip"));
+    assert_eq!(std::fs::read_to_string(&files[0].path).unwrap(), "video");
+  }
+
+  #[tokio::test]
+  async fn serves_a_directory_as_a_gallery() {
+    let files = fetch("fixture://album/", "gallery").await.unwrap();
+    let names: Vec<_> = files
+      .iter()
+      .map(|file| {
+        file
+          .path
+          .file_name()
+          .unwrap()
+          .to_string_lossy()
+          .into_owned()
+      })
+      .collect();
+    assert_eq!(names, ["1.png", "2.png"]);
+  }
+
+  #[tokio::test]
+  async fn fails_like_a_site() {
+    let login = fetch("fixture://login", "login").await;
+    assert!(matches!(login, Err(DownloadError::LoginRequired(_))));
+    let large = fetch("fixture://too-large", "large").await;
+    assert!(matches!(large, Err(DownloadError::TooLarge(_))));
+  }
+
+  #[tokio::test]
+  async fn refuses_missing_fixtures_and_climbing_out() {
+    for url in ["fixture://nope", "fixture://", "fixture://album/../../etc"] {
+      let result = fetch(url, "missing").await;
+      assert!(
+        matches!(result, Err(DownloadError::UnsupportedSite(_))),
+        "{url}"
+      );
+    }
+  }
+}
@@ -0,0 +1,69 @@
+use super::{route_args, Downloader, Fallback, File, Request};
+use crate::cookies;
+use crate::error::DownloadError;
+use async_trait::async_trait;
+use tokio::process::Command;
+
+/// Image posts and galleries, through gallery-dl. Every image lands in its own
+/// numbered file inside the request's `out` directory.
+pub struct GalleryDl;
+
+#[async_trait]
+impl Downloader for GalleryDl {
+  fn name(&self) -> &'static str {
+    "gallery-dl"
+  }
+
+  async fn download(&self, request: &Request<'_>) -> Result<Vec<File>, DownloadError> {
+    let mut cmd = Command::new("gallery-dl");
+    cmd
+      .arg("--directory")
+      .arg(request.out)
+      .arg("--filename")
+      .arg("{num:>03}.{extension}")
+      .arg("--filesize-max")
+      .arg(format!("{}M", request.size_limit_mb));
+
+    if !matches!(request.fallback, Fallback::WithoutCookies) {
+      if let Some(jar) = cookies::for_url(request.url) {
+        cmd.arg("--cookies").arg(jar);
+      }
+    }
+    if let Some(route) = request.route {
+      cmd.args(route_args(route));
+    }
+
+    let output = cmd.arg(request.url).output().await?;
+    if !output.status.success() {
+      let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+      return Err(classify(stderr));
+    }
+
+    let mut files: Vec<File> = std::fs::read_dir(request.out)?
+      .flatten()
+      .map(|entry| File {
+        path: entry.path(),
+        title: None,
+      })
+      .collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if files.is_empty() {
+      return Err(DownloadError::Downloader(
+        "gallery-dl found nothing to download".to_owned(),
+      ));
+    }
+    Ok(files)
+  }
+}
+
+fn classify(stderr: String) -> DownloadError {
+  let lower = stderr.to_lowercase();
+  if lower.contains("unsupported url") {
+    DownloadError::UnsupportedSite(stderr)
+  } else if lower.contains("login") || lower.contains("authentication") {
+    DownloadError::LoginRequired(stderr)
+  } else {
+    DownloadError::Downloader(stderr)
+  }
+}
@@ -0,0 +1,96 @@
+use super::{route_args, Downloader, Fallback, File, Request};
+use crate::config;
+use crate::cookies;
+use crate::error::DownloadError;
+use async_trait::async_trait;
+use glob::glob;
+use youtube_dl::{YoutubeDl, YoutubeDlOutput};
+
+/// Videos and audio from the thousand-odd sites yt-dlp knows.
+pub struct YtDlp;
+
+#[async_trait]
+impl Downloader for YtDlp {
+  fn name(&self) -> &'static str {
+    "yt-dlp"
+  }
+
+  async fn download(&self, request: &Request<'_>) -> Result<Vec<File>, DownloadError> {
+    let dl_cmd = dl_cmd(request);
+    let output = tokio::task::spawn_blocking(move || dl_cmd.run())
+      .await
+      .map_err(|err| DownloadError::Downloader(err.to_string()))??;
+
+    let title = match output {
+      YoutubeDlOutput::SingleVideo(video) => Some(video.title),
+      _ => None,
+    };
+
+    // whatever extension yt-dlp settled on, minus its leftovers
+    let path = glob(&format!("{}.*", request.out.display()))
+      .map_err(|err| DownloadError::Downloader(err.to_string()))?
+      .flatten()
+      .find(|path| {
+        !matches!(
+          path.extension().and_then(|e| e.to_str()),
+          Some("part" | "ytdl")
+        )
+      })
+      .ok_or_else(|| DownloadError::Downloader("yt-dlp didn't write a file".to_owned()))?;
+
+    Ok(vec![File { path, title }])
+  }
+}
+
+fn dl_cmd(request: &Request) -> YoutubeDl {
+  let settings = request.settings;
+  let mut dl_cmd = YoutubeDl::new(request.url);
+  dl_cmd
+    .socket_timeout("15")
+    .output_template(format!("{}.%(ext)s", request.out.display()))
+    .download(true);
+
+  if !matches!(request.fallback, Fallback::WithoutCookies) {
+    if let Some(jar) = cookies::for_url(request.url) {
+      dl_cmd.cookies(jar.to_string_lossy());
+    }
+  }
+
+  let sort = match request.fallback {
+    Fallback::LowerQuality => format!("res:480,filesize:{}M", request.size_limit_mb / 2),
+    _ => format!("filesize:{}M", request.size_limit_mb),
+  };
+  dl_cmd.extra_arg("-S").extra_arg(sort);
+
+  if matches!(request.fallback, Fallback::AlternateExtractor) {
+    dl_cmd
+      .extra_arg("--extractor-args")
+      .extra_arg(&config::get().download.extractor_args);
+  }
+
+  if let Some(route) = request.route {
+    for arg in route_args(route) {
+      dl_cmd.extra_arg(arg);
+    }
+  }
+
+  if request.audio_only {
+    dl_cmd.extract_audio(true);
+    if settings.audio_codec != "best" {
+      dl_cmd
+        .extra_arg("--audio-format")
+        .extra_arg(&settings.audio_codec);
+    }
+  } else {
+    if let Ok(height) = settings.resolution.parse::<u32>() {
+      dl_cmd.format(format!("bv*[height<={height}]+ba/b[height<={height}]/b"));
+    }
+    dl_cmd
+      .extra_arg("--merge-output-format")
+      .extra_arg(&settings.container)
+      .extra_arg("--remux-video")
+      .extra_arg(&settings.container);
+  }
+
+  dl_cmd
+}
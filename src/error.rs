@@ -0,0 +1,137 @@
+use crate::messages::{t, Template};
+use crate::settings::ChatSettings;
+use log::Level;
+use thiserror::Error;
+
+/// Everything that can go wrong while fetching something for a user and handing it back.
+#[derive(Debug, Error)]
+pub enum DownloadError {
+  #[error("no url given")]
+  MissingUrl,
+  #[error("no file given")]
+  MissingFile,
+  #[error("no route named {0}")]
+  UnknownRoute(String),
+  #[error("bad option: {0}")]
+  BadOption(String),
+  #[error("unsupported site: {0}")]
+  UnsupportedSite(String),
+  #[error("geo-blocked: {0}")]
+  GeoBlocked(String),
+  #[error("login required: {0}")]
+  LoginRequired(String),
+  #[error("too large: {0}")]
+  TooLarge(String),
+  /// yt-dlp (or the spotify client) exited badly or produced nothing.
+  #[error("downloader failed: {0}")]
+  Downloader(String),
+  #[error("storage failure: {0:?}")]
+  Storage(anyhow::Error),
+  #[error("telegram send failure: {0}")]
+  Telegram(#[from] teloxide::RequestError),
+}
+
+impl DownloadError {
+  /// Key of the message the user is shown, see `messages`.
+  pub fn message_key(&self) -> &'static str {
+    match self {
+      Self::MissingUrl => "no_url",
+      Self::MissingFile => "no_file",
+      Self::UnknownRoute(_) => "error_route",
+      Self::BadOption(_) => "error_option",
+      Self::UnsupportedSite(_) => "error_unsupported",
+      Self::GeoBlocked(_) => "error_geo",
+      Self::LoginRequired(_) => "error_login",
+      Self::TooLarge(_) => "error_too_large",
+      Self::Downloader(_) => "error_download",
+      Self::Storage(_) => "error_storage",
+      Self::Telegram(_) => "error_telegram",
+    }
+  }
+
+  /// Whether trying again, possibly differently, stands a chance of working.
+  /// Logins aren't, none of the fallbacks bring cookies that weren't there.
+  pub fn is_retryable(&self) -> bool {
+    matches!(self, Self::Downloader(_) | Self::TooLarge(_))
+  }
+
+  /// Problems with what the user asked for are routine, problems on our end are not.
+  pub fn log_level(&self) -> Level {
+    match self {
+      Self::MissingUrl | Self::MissingFile | Self::UnknownRoute(_) | Self::BadOption(_) => {
+        Level::Debug
+      }
+      Self::UnsupportedSite(_)
+      | Self::GeoBlocked(_)
+      | Self::LoginRequired(_)
+      | Self::TooLarge(_) => Level::Info,
+      Self::Telegram(_) => Level::Warn,
+      Self::Downloader(_) | Self::Storage(_) => Level::Error,
+    }
+  }
+}
+
+/// Why `/settings` couldn't change something.
+#[derive(Debug, Error)]
+pub enum SettingError {
+  #[error("no setting named {0}")]
+  UnknownKey(String),
+  #[error("{value} isn't one of {options}")]
+  NotAnOption { value: String, options: String },
+  #[error("{0} isn't a valid value")]
+  BadValue(String),
+}
+
+impl SettingError {
+  /// What the chat is told, see `messages`.
+  pub fn reply(&self, settings: &ChatSettings) -> Template {
+    match self {
+      Self::UnknownKey(key) => t(settings, "settings_unknown").arg("key", key),
+      Self::NotAnOption { value, options } => t(settings, "settings_not_an_option")
+        .arg("value", value)
+        .arg("options", options),
+      Self::BadValue(value) => t(settings, "settings_bad_value").arg("value", value),
+    }
+  }
+}
+
+impl From<youtube_dl::Error> for DownloadError {
+  fn from(err: youtube_dl::Error) -> Self {
+    let youtube_dl::Error::ExitCode { stderr, .. } = &err else {
+      return Self::Downloader(err.to_string());
+    };
+
+    let lower = stderr.to_lowercase();
+    let matches = |needles: &[&str]| needles.iter().any(|n| lower.contains(n));
+
+    if matches(&["unsupported url"]) {
+      Self::UnsupportedSite(stderr.clone())
+    } else if matches(&[
+      "your country",
+      "geo restrict",
+      "geo-restrict",
+      "your location",
+    ]) {
+      Self::GeoBlocked(stderr.clone())
+    } else if matches(&[
+      "sign in",
+      "login required",
+      "log in",
+      "private video",
+      "confirm your age",
+      "--cookies",
+    ]) {
+      Self::LoginRequired(stderr.clone())
+    } else if matches(&["max-filesize", "larger than max"]) {
+      Self::TooLarge(stderr.clone())
+    } else {
+      Self::Downloader(stderr.clone())
+    }
+  }
+}
+
+impl From<std::io::Error> for DownloadError {
+  fn from(err: std::io::Error) -> Self {
+    Self::Downloader(err.to_string())
+  }
+}
@@ -0,0 +1,528 @@
+use parking_lot::Mutex;
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  future::Future,
+  io,
+  sync::Arc,
+};
+use tokio::sync::watch;
+use tokio_stream::Stream;
+use warp::hyper::body::Bytes;
+
+/// Bytes a fetch keeps from the start of the body for requests that join it late.
+/// Once it has fetched more than this, new requests start a fetch of their own.
+const JOIN_WINDOW: u64 = 4_000_000;
+/// How far a fetch may get ahead of its fastest reader. Readers further behind than
+/// this are cut off, so a slow client can't make us hold the whole file.
+const MAX_LAG: u64 = 16_000_000;
+
+/// Fetches under way, by key, so requests for the same thing at the same time share
+/// one. `H` is whatever comes before the body, like a status and headers.
+pub struct Flights<H> {
+  inflight: Mutex<HashMap<String, Arc<Flight<H>>>>,
+}
+
+impl<H> Default for Flights<H> {
+  fn default() -> Self {
+    Self {
+      inflight: Mutex::default(),
+    }
+  }
+}
+
+struct Flight<H> {
+  state: Mutex<State<H>>,
+  /// Sent by the fetch when there's something new to read.
+  changed: watch::Sender<()>,
+  /// Sent by readers when they've read something or left.
+  advanced: watch::Sender<()>,
+}
+
+struct State<H> {
+  head: Option<H>,
+  chunks: VecDeque<Bytes>,
+  /// How many chunks were dropped from the front of `chunks`.
+  first: usize,
+  /// Bytes fetched so far, and how many of them are still in `chunks`.
+  fetched: u64,
+  buffered: u64,
+  /// Set once the fetch is over, to whether it got the whole body.
+  done: Option<bool>,
+  /// Whether new readers can still start from the first chunk.
+  open: bool,
+  /// Where each reader is.
+  readers: HashMap<u64, Position>,
+  /// Readers that fell too far behind.
+  cut_off: HashSet<u64>,
+  next_reader: u64,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Position {
+  /// The next chunk it wants.
+  chunk: usize,
+  /// Bytes it has had.
+  bytes: u64,
+}
+
+impl<H> State<H> {
+  /// How far the fetch is ahead of its fastest reader.
+  fn lead(&self) -> u64 {
+    let fastest = self.readers.values().map(|p| p.bytes).max();
+    self.fetched - fastest.unwrap_or(self.fetched)
+  }
+
+  fn cut_off_laggards(&mut self) {
+    let fetched = self.fetched;
+    let cut_off = &mut self.cut_off;
+    self.readers.retain(|id, position| {
+      let keep = fetched - position.bytes <= MAX_LAG;
+      if !keep {
+        cut_off.insert(*id);
+      }
+      keep
+    });
+  }
+
+  /// Drops chunks every reader has had, once nobody new can join.
+  fn prune(&mut self) {
+    if self.open {
+      return;
+    }
+    let end = self.first + self.chunks.len();
+    let wanted = self.readers.values().map(|p| p.chunk).min().unwrap_or(end);
+    while self.first < wanted {
+      let Some(chunk) = self.chunks.pop_front() else {
+        break;
+      };
+      self.buffered -= chunk.len() as u64;
+      self.first += 1;
+    }
+  }
+}
+
+impl<H: Clone + Send + 'static> Flights<H> {
+  /// Reads the fetch of `key` that's already under way, or spawns `fetch` to start one.
+  pub fn join<F, Fut>(&'static self, key: &str, fetch: F) -> Reader<H>
+  where
+    F: FnOnce(Sender<H>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+  {
+    let mut inflight = self.inflight.lock();
+    if let Some(flight) = inflight.get(key) {
+      if let Some(reader) = Reader::new(flight) {
+        return reader;
+      }
+    }
+
+    let flight = Arc::new(Flight {
+      state: Mutex::new(State {
+        head: None,
+        chunks: VecDeque::new(),
+        first: 0,
+        fetched: 0,
+        buffered: 0,
+        done: None,
+        open: true,
+        readers: HashMap::new(),
+        cut_off: HashSet::new(),
+        next_reader: 0,
+      }),
+      changed: watch::channel(()).0,
+      advanced: watch::channel(()).0,
+    });
+    // a fetch that's too far along to join keeps going for the readers it has
+    inflight.insert(key.to_owned(), flight.clone());
+    let reader = Reader::new(&flight).expect("a new flight is open");
+
+    tokio::spawn(fetch(Sender {
+      flights: self,
+      key: key.to_owned(),
+      advanced: flight.advanced.subscribe(),
+      flight,
+      complete: false,
+    }));
+    reader
+  }
+}
+
+impl<H> Flights<H> {
+  /// Whether something is fetching `key`, joinable or not.
+  pub fn contains(&self, key: &str) -> bool {
+    self.inflight.lock().contains_key(key)
+  }
+
+  fn forget(&self, key: &str, flight: &Arc<Flight<H>>) {
+    let mut inflight = self.inflight.lock();
+    if inflight.get(key).is_some_and(|f| Arc::ptr_eq(f, flight)) {
+      inflight.remove(key);
+    }
+  }
+}
+
+/// The fetching end of a flight. Dropping it without calling `finish` tells the
+/// readers the body was cut short.
+pub struct Sender<H: 'static> {
+  flights: &'static Flights<H>,
+  key: String,
+  flight: Arc<Flight<H>>,
+  advanced: watch::Receiver<()>,
+  complete: bool,
+}
+
+impl<H: Clone + Send + 'static> Sender<H> {
+  pub fn head(&self, head: H) {
+    self.flight.state.lock().head = Some(head);
+    self.flight.changed.send_replace(());
+  }
+
+  /// Hands a chunk to the readers, first waiting for the fastest of them to get
+  /// close enough to the end. Without readers it doesn't wait.
+  pub async fn push(&mut self, chunk: Bytes) {
+    let len = chunk.len() as u64;
+    loop {
+      self.advanced.borrow_and_update();
+      {
+        let state = self.flight.state.lock();
+        let lead = state.lead();
+        if lead == 0 || lead + len <= MAX_LAG {
+          break;
+        }
+      }
+      if self.advanced.changed().await.is_err() {
+        break;
+      }
+    }
+
+    {
+      let mut state = self.flight.state.lock();
+      state.fetched += len;
+      state.buffered += len;
+      state.chunks.push_back(chunk);
+      if state.fetched > JOIN_WINDOW {
+        state.open = false;
+      }
+      state.cut_off_laggards();
+      state.prune();
+    }
+    self.flight.changed.send_replace(());
+  }
+
+  /// Whether nobody's reading any more, so there's nobody left to fetch for.
+  pub fn abandoned(&self) -> bool {
+    self.flight.state.lock().readers.is_empty()
+  }
+
+  pub fn finish(mut self) {
+    self.complete = true;
+  }
+}
+
+impl<H: 'static> Drop for Sender<H> {
+  fn drop(&mut self) {
+    {
+      let mut state = self.flight.state.lock();
+      state.done = Some(self.complete);
+      state.open = false;
+      state.prune();
+    }
+    self.flights.forget(&self.key, &self.flight);
+    self.flight.changed.send_replace(());
+  }
+}
+
+/// One request's view of a flight, from the first chunk.
+pub struct Reader<H> {
+  flight: Arc<Flight<H>>,
+  id: u64,
+  position: Position,
+  changed: watch::Receiver<()>,
+}
+
+impl<H: Clone> Reader<H> {
+  fn new(flight: &Arc<Flight<H>>) -> Option<Self> {
+    let mut state = flight.state.lock();
+    if !state.open {
+      return None;
+    }
+    let id = state.next_reader;
+    state.next_reader += 1;
+    state.readers.insert(id, Position::default());
+
+    Some(Self {
+      flight: flight.clone(),
+      id,
+      position: Position::default(),
+      changed: flight.changed.subscribe(),
+    })
+  }
+
+  /// Waits for the head, `None` if the fetch gave up before it had one.
+  pub async fn head(&mut self) -> Option<H> {
+    loop {
+      self.changed.borrow_and_update();
+      {
+        let state = self.flight.state.lock();
+        if let Some(head) = &state.head {
+          return Some(head.clone());
+        }
+        if state.done.is_some() {
+          return None;
+        }
+      }
+      if self.changed.changed().await.is_err() {
+        return None;
+      }
+    }
+  }
+
+  async fn next_chunk(&mut self) -> Option<io::Result<Bytes>> {
+    let cut_short = || io::Error::new(io::ErrorKind::UnexpectedEof, "the fetch was cut short");
+    loop {
+      self.changed.borrow_and_update();
+      {
+        let mut state = self.flight.state.lock();
+        if state.cut_off.contains(&self.id) {
+          return Some(Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "fell too far behind the fetch",
+          )));
+        }
+        if let Some(chunk) = state.chunks.get(self.position.chunk - state.first).cloned() {
+          self.position.chunk += 1;
+          self.position.bytes += chunk.len() as u64;
+          state.readers.insert(self.id, self.position);
+          state.prune();
+          drop(state);
+          self.flight.advanced.send_replace(());
+          return Some(Ok(chunk));
+        }
+        match state.done {
+          Some(true) => return None,
+          Some(false) => return Some(Err(cut_short())),
+          None => {}
+        }
+      }
+      if self.changed.changed().await.is_err() {
+        return Some(Err(cut_short()));
+      }
+    }
+  }
+}
+
+impl<H: Clone + Send + 'static> Reader<H> {
+  pub fn into_stream(mut self) -> impl Stream<Item = io::Result<Bytes>> + Send {
+    async_stream::stream! {
+      while let Some(chunk) = self.next_chunk().await {
+        let failed = chunk.is_err();
+        yield chunk;
+        if failed {
+          break;
+        }
+      }
+    }
+  }
+}
+
+impl<H> Drop for Reader<H> {
+  fn drop(&mut self) {
+    {
+      let mut state = self.flight.state.lock();
+      state.readers.remove(&self.id);
+      state.cut_off.remove(&self.id);
+      state.prune();
+    }
+    // the fetch may have been waiting on this one
+    self.flight.advanced.send_replace(());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use tokio::sync::oneshot;
+  use tokio_stream::StreamExt;
+
+  type Head = &'static str;
+
+  fn flights() -> &'static Flights<Head> {
+    Box::leak(Box::default())
+  }
+
+  /// Joins `key`, handing back the sender when this started the fetch.
+  fn join(flights: &'static Flights<Head>, key: &str) -> (Reader<Head>, Option<Sender<Head>>) {
+    let (tx, rx) = oneshot::channel();
+    let mut started = false;
+    let reader = flights.join(key, |sender| {
+      started = true;
+      async move {
+        let _ = tx.send(sender);
+      }
+    });
+    let sender = started.then(|| {
+      // the fetch has been spawned, but hasn't necessarily run yet
+      tokio::task::block_in_place(|| rx.blocking_recv().unwrap())
+    });
+    (reader, sender)
+  }
+
+  fn chunk(len: usize) -> Bytes {
+    Bytes::from(vec![7; len])
+  }
+
+  async fn read_all(reader: Reader<Head>) -> io::Result<Vec<Bytes>> {
+    reader.into_stream().collect().await
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn requests_at_the_same_time_share_a_fetch() {
+    let flights = flights();
+    let (mut first, sender) = join(flights, "a");
+    let (mut second, again) = join(flights, "a");
+    let mut sender = sender.unwrap();
+    assert!(again.is_none());
+
+    sender.head("200");
+    sender.push(Bytes::from("hello ")).await;
+    sender.push(Bytes::from("there")).await;
+    sender.finish();
+
+    assert_eq!(first.head().await, Some("200"));
+    assert_eq!(second.head().await, Some("200"));
+    assert_eq!(read_all(first).await.unwrap().concat(), b"hello there");
+    assert_eq!(read_all(second).await.unwrap().concat(), b"hello there");
+    assert!(flights.inflight.lock().is_empty());
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn late_requests_start_from_the_beginning() {
+    let flights = flights();
+    let (first, sender) = join(flights, "b");
+    let mut sender = sender.unwrap();
+    sender.head("200");
+    sender.push(Bytes::from("one ")).await;
+
+    let (late, again) = join(flights, "b");
+    assert!(again.is_none());
+    sender.push(Bytes::from("two")).await;
+    sender.finish();
+
+    assert_eq!(read_all(first).await.unwrap().concat(), b"one two");
+    assert_eq!(read_all(late).await.unwrap().concat(), b"one two");
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn requests_past_the_window_get_a_fetch_of_their_own() {
+    let flights = flights();
+    let (_first, sender) = join(flights, "c");
+    let mut sender = sender.unwrap();
+    sender.push(chunk(JOIN_WINDOW as usize + 1)).await;
+
+    let (_late, own) = join(flights, "c");
+    assert!(own.is_some());
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn readers_hear_when_a_fetch_is_cut_short() {
+    let flights = flights();
+    let (mut reader, sender) = join(flights, "d");
+    let mut sender = sender.unwrap();
+    sender.head("200");
+    sender.push(Bytes::from("half")).await;
+    drop(sender);
+
+    assert_eq!(reader.head().await, Some("200"));
+    let chunks: Vec<_> = reader.into_stream().collect().await;
+    assert_eq!(chunks[0].as_ref().unwrap(), "half");
+    assert_eq!(
+      chunks[1].as_ref().unwrap_err().kind(),
+      io::ErrorKind::UnexpectedEof
+    );
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn a_fetch_that_gave_up_has_no_head() {
+    let flights = flights();
+    let (mut reader, sender) = join(flights, "e");
+    drop(sender);
+    assert_eq!(reader.head().await, None);
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn chunks_everyone_has_are_dropped_once_the_window_closes() {
+    let flights = flights();
+    let (reader, sender) = join(flights, "f");
+    let mut sender = sender.unwrap();
+    let mut stream = Box::pin(reader.into_stream());
+
+    sender.push(chunk(1_000_000)).await;
+    stream.next().await.unwrap().unwrap();
+    // still open, so a late request could want it
+    assert_eq!(sender.flight.state.lock().buffered, 1_000_000);
+
+    sender.push(chunk(JOIN_WINDOW as usize)).await;
+    assert_eq!(
+      sender.flight.state.lock().buffered,
+      JOIN_WINDOW,
+      "the first chunk was read by everyone"
+    );
+    stream.next().await.unwrap().unwrap();
+    assert_eq!(sender.flight.state.lock().buffered, 0);
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn the_fetch_waits_for_its_fastest_reader() {
+    let flights = flights();
+    let (reader, sender) = join(flights, "g");
+    let mut sender = sender.unwrap();
+    let mut stream = Box::pin(reader.into_stream());
+
+    let size = MAX_LAG as usize / 4;
+    for _ in 0..4 {
+      sender.push(chunk(size)).await;
+    }
+    let fifth = chunk(size);
+    let blocked = tokio::time::timeout(Duration::from_millis(100), sender.push(fifth.clone()));
+    assert!(blocked.await.is_err(), "pushed past the reader");
+
+    stream.next().await.unwrap().unwrap();
+    sender.push(fifth).await;
+    assert!(sender.flight.state.lock().buffered <= MAX_LAG);
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn slow_readers_are_cut_off() {
+    let flights = flights();
+    let (fast, sender) = join(flights, "h");
+    let (slow, _) = join(flights, "h");
+    let mut sender = sender.unwrap();
+    let mut fast = Box::pin(fast.into_stream());
+
+    let size = MAX_LAG as usize / 4;
+    for _ in 0..6 {
+      sender.push(chunk(size)).await;
+      fast.next().await.unwrap().unwrap();
+    }
+    sender.finish();
+    assert!(fast.next().await.is_none());
+
+    let slow: Vec<_> = slow.into_stream().collect().await;
+    assert_eq!(
+      slow[0].as_ref().unwrap_err().kind(),
+      io::ErrorKind::TimedOut
+    );
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn a_fetch_without_readers_is_abandoned() {
+    let flights = flights();
+    let (reader, sender) = join(flights, "i");
+    let mut sender = sender.unwrap();
+    assert!(!sender.abandoned());
+    drop(reader);
+    assert!(sender.abandoned());
+    // and doesn't wait for anyone
+    sender.push(chunk(MAX_LAG as usize * 2)).await;
+  }
+}
@@ -0,0 +1,138 @@
+use crate::config::{encode_path, ProxyRoute};
+use crate::{backblaze, index};
+use anyhow::Result;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use s3::serde_types::Object;
+use std::{
+  collections::HashMap,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+const PAGE_SIZE: usize = 60;
+const INDEX_HTML: &str = include_str!("web/index.html");
+/// Pages are served as fresh for a minute, so their listing is reused for as long.
+const LISTING_TTL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+  /// Recent listings by `<bucket>/<prefix>`, so paging through a folder lists it once.
+  static ref LISTINGS: Mutex<HashMap<String, (Instant, Arc<Listing>)>> = Mutex::default();
+}
+
+/// A folder's subfolders and its files, newest first.
+struct Listing {
+  dirs: Vec<String>,
+  files: Vec<Object>,
+}
+
+/// A page of what's under `prefix` in the route's bucket, newest first. Folders come
+/// first, on the first page. Links are relative, the page is served from `title`.
+pub async fn render(route: &ProxyRoute, prefix: &str, title: &str, page: usize) -> Result<String> {
+  let listing = listing(route, prefix).await?;
+  let Listing { dirs, files } = listing.as_ref();
+
+  let pages = files.len().div_ceil(PAGE_SIZE).max(1);
+  let page = page.clamp(1, pages);
+
+  let mut items = vec![];
+  if page == 1 {
+    for dir in dirs {
+      let name = dir.strip_prefix(prefix).unwrap_or(dir);
+      items.push(format!(
+        r#"<a href="{}" class="p-4 rounded-md bg-white hover:bg-slate-200">📁 {}</a>"#,
+        encode_path(name),
+        escape(name.trim_end_matches('/'))
+      ));
+    }
+  }
+  for file in files.iter().skip((page - 1) * PAGE_SIZE).take(PAGE_SIZE) {
+    let name = file.key.strip_prefix(prefix).unwrap_or(&file.key);
+    items.push(item(name, file.size, &file.last_modified));
+  }
+  if items.is_empty() {
+    items.push(r#"<p class="text-slate-500">Nothing here.</p>"#.to_owned());
+  }
+
+  let mut nav = vec![];
+  if page > 1 {
+    nav.push(format!(r#"<a href="?page={}">← newer</a>"#, page - 1));
+  }
+  if pages > 1 {
+    nav.push(format!("<span>{page} / {pages}</span>"));
+  }
+  if page < pages {
+    nav.push(format!(r#"<a href="?page={}">older →</a>"#, page + 1));
+  }
+
+  Ok(
+    INDEX_HTML
+      .replace("{title}", &escape(title))
+      .replace("{items}", &items.join("\n"))
+      .replace("{pages}", &nav.join("\n")),
+  )
+}
+
+/// What's under `prefix`, listed again once the last listing is older than
+/// `LISTING_TTL`.
+async fn listing(route: &ProxyRoute, prefix: &str) -> Result<Arc<Listing>> {
+  let key = format!("{}/{prefix}", route.bucket);
+  let recent = LISTINGS
+    .lock()
+    .get(&key)
+    .filter(|(listed, _)| listed.elapsed() < LISTING_TTL)
+    .map(|(_, listing)| listing.clone());
+  if let Some(listing) = recent {
+    return Ok(listing);
+  }
+
+  let (dirs, mut files) = backblaze::list_dir(&route.bucket, prefix).await?;
+  // some tools leave an empty object standing in for the folder itself
+  files.retain(|file| file.key != prefix && !index::is_expired(&route.bucket, &file.key));
+  // timestamps are ISO 8601, so they sort as strings
+  files.sort_by(|a, b| b.last_modified.cmp(&a.last_modified));
+
+  let listing = Arc::new(Listing { dirs, files });
+  let mut listings = LISTINGS.lock();
+  listings.retain(|_, (listed, _)| listed.elapsed() < LISTING_TTL);
+  listings.insert(key, (Instant::now(), listing.clone()));
+  Ok(listing)
+}
+
+/// A file as a thumbnail or player, depending on what it is.
+fn item(name: &str, size: u64, modified: &str) -> String {
+  let href = encode_path(name);
+  let label = escape(name);
+  let mime = mime_guess::from_path(name).first_or_octet_stream();
+
+  let preview = match mime.type_().as_str() {
+    "image" => format!(
+      r#"<img src="{href}" alt="{label}" loading="lazy" class="w-full h-48 object-cover rounded-md" />"#
+    ),
+    "video" => format!(
+      r#"<video src="{href}" controls preload="metadata" class="w-full h-48 rounded-md bg-black"></video>"#
+    ),
+    "audio" => format!(r#"<audio src="{href}" controls preload="none" class="w-full"></audio>"#),
+    _ => String::new(),
+  };
+
+  format!(
+    r#"<div class="p-2 rounded-md bg-white flex flex-col gap-2">{preview}<a href="{href}" class="truncate hover:underline">{label}</a><span class="text-sm text-slate-500">{} · {}</span></div>"#,
+    modified.get(..10).unwrap_or(modified),
+    match size {
+      0..=999_999 => format!("{}KB", size.div_ceil(1_000)),
+      _ => format!("{:.1}MB", size as f64 / 1_000_000.),
+    }
+  )
+}
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+    .replace('\'', "&#39;")
+    // so names can't look like the template's placeholders
+    .replace('{', "&#123;")
+}
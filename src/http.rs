@@ -1,37 +1,60 @@
+use crate::cache;
+use crate::cert;
+use crate::config::{self, HttpConfig, ProxyRoute};
+use crate::flight::{self, Flights};
+use crate::gallery;
+use crate::index;
 use crate::music::{dl_thread, search};
+use crate::stats;
+use futures::{Stream, StreamExt};
 use lazy_static::lazy_static;
-use parking_lot::{Mutex, RwLock};
+use parking_lot::Mutex;
+use percent_encoding::percent_decode_str;
 use rspotify_model::idtypes::Id;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::path::Path;
 use std::{
-  collections::{HashMap, VecDeque},
+  collections::HashMap,
   convert::Infallible,
-  pin::Pin,
-  task::{Context, Poll},
-  thread,
-  time::Duration,
+  io, thread,
+  time::{Duration, UNIX_EPOCH},
 };
-use tokio_stream::Stream;
 use warp::{
-  http::Response,
+  http::{
+    header::{
+      HeaderName, HeaderValue, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH,
+      CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED,
+      RANGE,
+    },
+    response::Builder,
+    HeaderMap, Response, StatusCode,
+  },
   hyper::body::{Body, Bytes},
+  path::FullPath,
   Filter,
 };
 
-const FILESIZE_LIMIT: usize = 5_000_000; // in bytes
-const FILE_COUNT_LIMIT: usize = 10;
-const LETS_ENCRYPT_ACCOUNT: &str = "17287977548916597336";
+/// Bearer token for `/admin`, which doesn't exist without one.
+const ADMIN_TOKEN: Option<&str> = option_env!("SMEE_ADMIN_TOKEN");
+/// Origin headers worth handing on to the client as they are. The validators are
+/// handled by `Validators`.
+const PASSTHROUGH_HEADERS: [HeaderName; 3] = [CONTENT_LENGTH, CONTENT_RANGE, CONTENT_DISPOSITION];
 
 lazy_static! {
-  static ref FILE_CACHE: RwLock<(HashMap<String, Vec<u8>>, VecDeque<String>)> = RwLock::default();
   pub static ref ACME_PROOF: Mutex<String> = Mutex::new(String::from("DEFAULT"));
+  static ref CLIENT: reqwest::Client = reqwest::Client::new();
+  static ref FLIGHTS: Flights<Fetched> = Flights::default();
 }
 
 pub async fn serve(port: u16) -> anyhow::Result<()> {
   info!("Booting web server...");
 
   // build routes
-  let root = warp::path::end().and_then(root);
+  let root = warp::path::end()
+    .and(warp::header::optional::<String>("host"))
+    .and(warp::query::<HashMap<String, String>>())
+    .and_then(root);
   let acme_challenge = warp::path(".well-known")
     .and(warp::path("acme-challenge"))
     .and(warp::path::param())
@@ -42,8 +65,11 @@ pub async fn serve(port: u16) -> anyhow::Result<()> {
       });
       ACME_PROOF.lock().clone()
     });
-  let img = warp::path::param().and_then(img);
-  let vid = warp::path("v").and(warp::path::param()).and_then(vid);
+  let file = warp::header::optional::<String>("host")
+    .and(warp::path::full())
+    .and(warp::query::<HashMap<String, String>>())
+    .and(warp::header::headers_cloned())
+    .and_then(file);
   let song = warp::path("song-priv")
     .and(warp::query::<HashMap<String, String>>())
     .and_then(song);
@@ -51,13 +77,33 @@ pub async fn serve(port: u16) -> anyhow::Result<()> {
     .and(warp::path::param())
     .and_then(dl_song);
 
+  let admin = warp::path("admin")
+    .and(warp::path("cache"))
+    .and(warp::header::optional::<String>("authorization"));
+  let cache_list = admin
+    .and(warp::path::end())
+    .and(warp::get())
+    .and_then(cache_list);
+  let cache_warm = admin
+    .and(warp::path("warm"))
+    .and(warp::path::end())
+    .and(warp::post())
+    .and(warp::body::json())
+    .and_then(cache_warm);
+  let cache_purge = admin
+    .and(warp::path::tail())
+    .and(warp::delete())
+    .and_then(cache_purge);
+
   // collect routes
-  let routes = warp::get().and(root.or(dl_song).or(song).or(acme_challenge).or(vid).or(img));
+  let routes = cache_list
+    .or(cache_warm)
+    .or(cache_purge)
+    .or(warp::get().and(root.or(dl_song).or(song).or(acme_challenge).or(file)));
 
   let server = warp::serve(routes);
 
-  let cert_file = format!("{LETS_ENCRYPT_ACCOUNT}_crt_kota_is.crt");
-  let key_file = format!("{LETS_ENCRYPT_ACCOUNT}_key_kota_is.key");
+  let (cert_file, key_file) = (cert::cert_file(), cert::key_file());
   if Path::new(&cert_file).exists() && Path::new(&key_file).exists() {
     let server = server.tls().cert_path(cert_file).key_path(key_file);
     server.run(([0, 0, 0, 0], port)).await;
@@ -68,15 +114,229 @@ pub async fn serve(port: u16) -> anyhow::Result<()> {
   Ok(())
 }
 
-async fn root() -> Result<impl warp::Reply, Infallible> {
-  Ok("Hello there.")
+/// The gallery of a route at `/` with one, a greeting otherwise.
+async fn root(
+  host: Option<String>,
+  query: HashMap<String, String>,
+) -> Result<Response<Body>, Infallible> {
+  if gallery_for(&config::get().http, host.as_deref(), "/").is_none() {
+    return Ok(plain(StatusCode::OK, "Hello there."));
+  }
+  Ok(directory(host.as_deref(), "/", page(&query)).await)
+}
+
+fn page(query: &HashMap<String, String>) -> usize {
+  query.get("page").and_then(|p| p.parse().ok()).unwrap_or(1)
+}
+
+async fn file(
+  host: Option<String>,
+  path: FullPath,
+  query: HashMap<String, String>,
+  headers: HeaderMap,
+) -> Result<Response<Body>, Infallible> {
+  if path.as_str().ends_with('/') {
+    return Ok(directory(host.as_deref(), path.as_str(), page(&query)).await);
+  }
+
+  match locate(host.as_deref(), path.as_str()) {
+    Some((route, key)) => proxy(route, &key, headers).await,
+    None => Ok(plain(StatusCode::NOT_FOUND, "Nothing here.")),
+  }
+}
+
+/// A gallery page for a path ending in `/`, on routes that have them.
+async fn directory(host: Option<&str>, path: &str, page: usize) -> Response<Body> {
+  let Some((route, dir)) = gallery_for(&config::get().http, host, path) else {
+    return plain(StatusCode::NOT_FOUND, "Nothing here.");
+  };
+
+  let title = percent_decode_str(path).decode_utf8_lossy();
+  match gallery::render(route, &route.key(&dir), &title, page).await {
+    Ok(html) => Response::builder()
+      .header(CONTENT_TYPE, "text/html; charset=utf-8")
+      .header(CACHE_CONTROL, "public, max-age=60")
+      .body(Body::from(html))
+      .unwrap(),
+    Err(e) => {
+      error!("Couldn't list {}/{dir}: {e:?}", route.bucket);
+      plain(StatusCode::BAD_GATEWAY, "Couldn't reach storage.")
+    }
+  }
+}
+
+/// The route with a gallery for a path ending in `/`, and the folder of it to show.
+fn gallery_for<'a>(
+  http: &'a HttpConfig,
+  host: Option<&str>,
+  path: &str,
+) -> Option<(&'a ProxyRoute, String)> {
+  let (route, rest) = http.route_for(host, path)?;
+  if !route.index {
+    return None;
+  }
+  let dir = match rest.strip_suffix('/') {
+    Some(rest) => format!("{}/", normalize(rest)?),
+    // the route's own prefix
+    None => String::new(),
+  };
+  Some((route, dir))
+}
+
+/// The route and key behind a request path.
+pub(crate) fn locate(host: Option<&str>, path: &str) -> Option<(&'static ProxyRoute, String)> {
+  let (route, rest) = config::get().http.route_for(host, path)?;
+  Some((route, route.key(&normalize(rest)?)))
+}
+
+/// The route and key behind a public path like `v/abcde.mp4` on the public host.
+pub(crate) fn resolve(path: &str) -> Option<(&'static ProxyRoute, String)> {
+  let host = &config::get().http.public_host;
+  locate(Some(host), &format!("/{}", path.trim_start_matches('/')))
+}
+
+/// Decodes a url path into a key, one segment at a time. Anything that could climb
+/// out of the route's prefix, however it's encoded, gets `None`, and so do empty
+/// segments.
+fn normalize(path: &str) -> Option<String> {
+  let segments = path.split('/').map(|segment| {
+    let segment = percent_decode_str(segment).decode_utf8().ok()?;
+    let climbs = matches!(segment.as_ref(), "" | "." | "..")
+      || segment.contains(['/', '\\'])
+      || segment.chars().any(char::is_control);
+    (!climbs).then(|| segment.into_owned())
+  });
+  Some(segments.collect::<Option<Vec<String>>>()?.join("/"))
+}
+
+/// Checks an admin request's `Authorization` header, answering for it when it fails.
+fn admin_denied(authorization: Option<String>) -> Option<Response<Body>> {
+  let Some(token) = ADMIN_TOKEN else {
+    return Some(plain(StatusCode::NOT_FOUND, "Nothing here."));
+  };
+  match authorization
+    .as_deref()
+    .and_then(|auth| auth.strip_prefix("Bearer "))
+  {
+    Some(given) if same_secret(given, token) => None,
+    _ => Some(plain(StatusCode::UNAUTHORIZED, "Who goes there?")),
+  }
+}
+
+/// Compares secrets in constant time. Hashing first means the length doesn't show either.
+fn same_secret(given: &str, secret: &str) -> bool {
+  let (given, secret) = (Sha256::digest(given), Sha256::digest(secret));
+  given
+    .iter()
+    .zip(secret.iter())
+    .fold(0, |differs, (a, b)| differs | (a ^ b))
+    == 0
 }
 
-async fn img(path: String) -> Result<Response<Body>, Infallible> {
-  proxy("i-kota", &path).await
+fn json(status: StatusCode, value: &impl Serialize) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header(CONTENT_TYPE, "application/json")
+    .body(Body::from(serde_json::to_vec(value).unwrap_or_default()))
+    .unwrap()
 }
-async fn vid(path: String) -> Result<Response<Body>, Infallible> {
-  proxy("v-kota", &path).await
+
+async fn cache_list(authorization: Option<String>) -> Result<Response<Body>, Infallible> {
+  if let Some(denied) = admin_denied(authorization) {
+    return Ok(denied);
+  }
+  Ok(json(StatusCode::OK, &cache::list()))
+}
+
+#[derive(Deserialize)]
+struct WarmRequest {
+  /// Public paths, like `abcde.png` or `v/abcde.mp4`.
+  paths: Vec<String>,
+}
+
+async fn cache_warm(
+  authorization: Option<String>,
+  request: WarmRequest,
+) -> Result<Response<Body>, Infallible> {
+  if let Some(denied) = admin_denied(authorization) {
+    return Ok(denied);
+  }
+
+  let warming = request.paths.iter().map(|path| async move {
+    let warmed = match resolve(path) {
+      Some((route, key)) => warm_route(route, &key).await,
+      None => Err(StatusCode::NOT_FOUND),
+    };
+    let result = match warmed {
+      Ok(true) => "warmed".to_owned(),
+      Ok(false) => "cached".to_owned(),
+      Err(status) => status.as_u16().to_string(),
+    };
+    (path.clone(), result)
+  });
+  let results: HashMap<String, String> = futures::future::join_all(warming)
+    .await
+    .into_iter()
+    .collect();
+  Ok(json(StatusCode::OK, &results))
+}
+
+async fn cache_purge(
+  authorization: Option<String>,
+  path: warp::path::Tail,
+) -> Result<Response<Body>, Infallible> {
+  if let Some(denied) = admin_denied(authorization) {
+    return Ok(denied);
+  }
+
+  let cache_key = match resolve(path.as_str()) {
+    Some((route, key)) => format!("{}/{key}", route.bucket),
+    None => return Ok(plain(StatusCode::NOT_FOUND, "Nothing here.")),
+  };
+  if !cache::contains(&cache_key) {
+    return Ok(plain(StatusCode::NOT_FOUND, "That isn't cached."));
+  }
+  cache::evict(&cache_key).await;
+  Ok(
+    Response::builder()
+      .status(StatusCode::NO_CONTENT)
+      .body(Body::empty())
+      .unwrap(),
+  )
+}
+
+/// Fetches a file into the cache so its first viewer gets a hit. Returns whether it
+/// had to, or the status the origin answered with when that failed.
+pub async fn warm(bucket: &str, key: &str) -> Result<bool, StatusCode> {
+  match config::get().http.route_to(bucket) {
+    Some(route) => warm_route(route, key).await,
+    None => Err(StatusCode::NOT_FOUND),
+  }
+}
+
+async fn warm_route(route: &ProxyRoute, key: &str) -> Result<bool, StatusCode> {
+  let cache_key = format!("{}/{key}", route.bucket);
+  if cache::contains(&cache_key) {
+    return Ok(false);
+  }
+
+  let url = route.url(key);
+  let mut reader = FLIGHTS.join(&cache_key, |flight| {
+    fetch_shared(url, cache_key.clone(), flight)
+  });
+  match reader.head().await {
+    Some(Ok(_)) => {}
+    Some(Err((status, _))) => return Err(status),
+    None => return Err(StatusCode::BAD_GATEWAY),
+  }
+
+  let mut body = Box::pin(reader.into_stream());
+  while let Some(chunk) = body.next().await {
+    if chunk.is_err() {
+      return Err(StatusCode::BAD_GATEWAY);
+    }
+  }
+  Ok(true)
 }
 
 async fn dl_song(track: String) -> Result<Response<Body>, Infallible> {
@@ -130,92 +390,739 @@ async fn song(params: HashMap<String, String>) -> Result<Response<Body>, Infalli
   Ok(Response::builder().body(Body::from(song_html)).unwrap())
 }
 
-async fn proxy(bucket: &str, path: &str) -> Result<Response<Body>, Infallible> {
+/// What a `Range` header asks for out of a body of some length.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+  Whole,
+  /// Inclusive bounds.
+  Partial(u64, u64),
+  Unsatisfiable,
+}
+
+/// Resolves a `Range` header against a body of `len` bytes. Only single `bytes=` ranges
+/// are supported, anything else gets the whole body, which the spec allows.
+fn byte_range(header: Option<&str>, len: u64) -> ByteRange {
+  let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+    return ByteRange::Whole;
+  };
+  if spec.contains(',') {
+    return ByteRange::Whole;
+  }
+  let Some((start, end)) = spec.split_once('-') else {
+    return ByteRange::Whole;
+  };
+
+  let (start, end) = match (start.trim().parse::<u64>(), end.trim().parse::<u64>()) {
+    // the last n bytes
+    (Err(_), Ok(n)) if start.trim().is_empty() => match n {
+      0 => return ByteRange::Unsatisfiable,
+      n => (len.saturating_sub(n), len.saturating_sub(1)),
+    },
+    (Ok(start), Err(_)) if end.trim().is_empty() => (start, len.saturating_sub(1)),
+    (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+    _ => return ByteRange::Whole,
+  };
+
+  if start >= len {
+    return ByteRange::Unsatisfiable;
+  }
+  ByteRange::Partial(start, end)
+}
+
+/// What a client can check its copy of a file against.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Validators {
+  etag: Option<String>,
+  last_modified: Option<String>,
+}
+
+impl Validators {
+  /// Reads them off an origin response. B2 doesn't always send `ETag` or
+  /// `Last-Modified`, but it always has the content's sha1 and upload time.
+  fn from_origin(headers: &HeaderMap) -> Self {
+    let bz = |name: &str| headers.get(name).and_then(|h| h.to_str().ok());
+
+    let header = |name: HeaderName| Some(headers.get(name)?.to_str().ok()?.to_owned());
+
+    let etag = header(ETAG).or_else(|| {
+      // large files uploaded in parts have no sha1
+      let sha1 = bz("x-bz-content-sha1").filter(|sha1| !sha1.contains("none"))?;
+      Some(format!("\"{}\"", sha1.trim_start_matches("unverified:")))
+    });
+    let last_modified = header(LAST_MODIFIED).or_else(|| {
+      let millis: u64 = bz("x-bz-upload-timestamp")?.parse().ok()?;
+      Some(httpdate::fmt_http_date(
+        UNIX_EPOCH + Duration::from_millis(millis),
+      ))
+    });
+
+    Self {
+      etag,
+      last_modified,
+    }
+  }
+
+  /// Whether the client's copy is still good. `If-None-Match` wins over
+  /// `If-Modified-Since` when both are sent.
+  fn not_modified(&self, request: &HeaderMap) -> bool {
+    if let Some(tags) = request.get(IF_NONE_MATCH).and_then(|h| h.to_str().ok()) {
+      let Some(etag) = &self.etag else {
+        return false;
+      };
+      let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+      return tags
+        .split(',')
+        .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag));
+    }
+
+    let date = |value: Option<&str>| httpdate::parse_http_date(value?).ok();
+    let since = request.get(IF_MODIFIED_SINCE).and_then(|h| h.to_str().ok());
+    match (date(since), date(self.last_modified.as_deref())) {
+      (Some(since), Some(modified)) => modified <= since,
+      _ => false,
+    }
+  }
+
+  /// Whether a `Range` should be honoured given the request's `If-Range`.
+  fn range_applies(&self, request: &HeaderMap) -> bool {
+    let Some(if_range) = request.get(IF_RANGE) else {
+      return true;
+    };
+    match if_range.to_str().unwrap_or_default() {
+      // weak tags never match here
+      tag if tag.starts_with("W/") => false,
+      tag if tag.starts_with('"') => self.etag.as_ref().is_some_and(|etag| etag == tag),
+      date => self.last_modified.as_deref() == Some(date),
+    }
+  }
+
+  fn add_to(&self, mut builder: Builder) -> Builder {
+    if let Some(etag) = &self.etag {
+      builder = builder.header(ETAG, etag);
+    }
+    if let Some(last_modified) = &self.last_modified {
+      builder = builder.header(LAST_MODIFIED, last_modified);
+    }
+    builder
+  }
+}
+
+/// The configured `Cache-Control` for `route`, cut short for links that expire.
+fn cache_control(route: &ProxyRoute, key: &str) -> Option<String> {
+  let configured = route.cache_control.as_ref()?;
+  match index::get(&route.bucket, key).and_then(|entry| entry.expires) {
+    Some(expires) => Some(format!(
+      "public, max-age={}",
+      expires.saturating_sub(index::now())
+    )),
+    None => Some(configured.clone()),
+  }
+}
+
+/// Headers every answer about a file carries, whatever its status.
+fn file_headers(validators: &Validators, cache_control: Option<&str>) -> Builder {
+  let mut builder = validators.add_to(Response::builder().header(ACCEPT_RANGES, "bytes"));
+  if let Some(cache_control) = cache_control {
+    builder = builder.header(CACHE_CONTROL, cache_control);
+  }
+  builder
+}
+
+/// Serves a cached file, or the part of it the request asks for.
+fn cached_response(
+  hit: cache::Hit,
+  content_type: &str,
+  cache_control: Option<&str>,
+  headers: &HeaderMap,
+) -> Response<Body> {
+  let len = hit.size;
+  let builder = file_headers(&hit.validators, cache_control);
+
+  if hit.validators.not_modified(headers) {
+    return not_modified(&hit.validators, cache_control);
+  }
+
+  let range = match hit.validators.range_applies(headers) {
+    true => byte_range(headers.get(RANGE).and_then(|h| h.to_str().ok()), len),
+    false => ByteRange::Whole,
+  };
+
+  let builder = builder.header(CONTENT_TYPE, content_type);
+  match range {
+    ByteRange::Whole => {
+      stats::incr(&stats::BYTES_SERVED, len);
+      builder
+        .header(CONTENT_LENGTH, len)
+        .body(hit.read(0, len))
+        .unwrap()
+    }
+    ByteRange::Partial(start, end) => {
+      stats::incr(&stats::BYTES_SERVED, end + 1 - start);
+      builder
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(CONTENT_RANGE, format!("bytes {start}-{end}/{len}"))
+        .header(CONTENT_LENGTH, end + 1 - start)
+        .body(hit.read(start, end + 1 - start))
+        .unwrap()
+    }
+    ByteRange::Unsatisfiable => builder
+      .status(StatusCode::RANGE_NOT_SATISFIABLE)
+      .header(CONTENT_RANGE, format!("bytes */{len}"))
+      .body(Body::empty())
+      .unwrap(),
+  }
+}
+
+async fn proxy(
+  route: &'static ProxyRoute,
+  path: &str,
+  headers: HeaderMap,
+) -> Result<Response<Body>, Infallible> {
+  let bucket = &route.bucket;
+  // the reaper may not have gotten to it yet
+  if index::is_expired(bucket, path) {
+    return Ok(plain(StatusCode::GONE, "This link has expired."));
+  }
+
   let guess = mime_guess::from_path(path).first_or(
     "application/octet-stream"
       .parse::<mime_guess::mime::Mime>()
       .unwrap(),
   );
   let content_type = format!("{}/{}", guess.type_(), guess.subtype());
+  let cache_control = cache_control(route, path);
+  let cache_key = format!("{bucket}/{path}");
 
-  if let Some(val) = FILE_CACHE.read().0.get(path) {
+  if let Some(hit) = cache::get(&cache_key).await {
     info!("RETURNED CACHED!");
-    return Ok(
-      Response::builder()
-        .header("Content-Type", content_type)
-        .body(Body::from(val.clone()))
-        .unwrap(),
-    );
+    return Ok(cached_response(
+      hit,
+      &content_type,
+      cache_control.as_deref(),
+      &headers,
+    ));
   }
 
   println!("{path}");
+  let url = route.url(path);
 
-  let response = reqwest::get(format!("https://f001.backblazeb2.com/file/{bucket}/{path}"))
-    .await
-    .unwrap();
+  // players open with `bytes=0-`, which the whole file answers just as well. Anywhere
+  // else the origin does the slicing, and the file gets cached behind it for later.
+  let range = headers.get(RANGE).and_then(|h| h.to_str().ok());
+  if range.is_some_and(|range| !from_start(range)) {
+    let response = fetch_range(&url, &content_type, cache_control.as_deref(), &headers).await;
+    fill_behind(route, path, &response);
+    return Ok(response);
+  }
 
-  let content_length = response.content_length();
-  let bytes_stream = response.bytes_stream();
-  let cacher = StreamCache {
-    stream: Mutex::new(Box::pin(bytes_stream)),
-    file: Mutex::default(),
-    path: path.to_owned(),
-    content_length,
+  let mut reader = FLIGHTS.join(&cache_key, |flight| {
+    fetch_shared(url, cache_key.clone(), flight)
+  });
+  let origin = match reader.head().await {
+    Some(Ok(origin)) => origin,
+    Some(Err((status, message))) => return Ok(plain(status, message)),
+    None => return Ok(plain(StatusCode::BAD_GATEWAY, "Couldn't reach storage.")),
   };
 
-  let wrapped_stream = Body::wrap_stream(cacher);
-  let response_stream = Response::builder()
-    .header("Content-Type", content_type)
-    .body(wrapped_stream)
-    .unwrap();
+  // the shared fetch is unconditional, so every request checks for itself
+  if origin.validators.not_modified(&headers) {
+    return Ok(not_modified(&origin.validators, cache_control.as_deref()));
+  }
 
-  Ok(response_stream)
+  let range = match (range, origin.size) {
+    (Some(range), Some(size)) if origin.validators.range_applies(&headers) => {
+      byte_range(Some(range), size)
+    }
+    _ => ByteRange::Whole,
+  };
+  let (origin, start, len) = match range {
+    ByteRange::Whole => (origin, 0, u64::MAX),
+    ByteRange::Partial(start, end) => (origin.partial(start, end), start, end + 1 - start),
+    ByteRange::Unsatisfiable => {
+      return Ok(
+        file_headers(&origin.validators, cache_control.as_deref())
+          .status(StatusCode::RANGE_NOT_SATISFIABLE)
+          .header(
+            CONTENT_RANGE,
+            format!("bytes */{}", origin.size.unwrap_or_default()),
+          )
+          .body(Body::empty())
+          .unwrap(),
+      )
+    }
+  };
+
+  let body = slice(reader.into_stream(), start, len).inspect(|chunk| {
+    if let Ok(chunk) = chunk {
+      stats::incr(&stats::BYTES_SERVED, chunk.len() as u64);
+    }
+  });
+  Ok(origin.respond(
+    &content_type,
+    cache_control.as_deref(),
+    Body::wrap_stream(body),
+  ))
 }
 
-struct StreamCache {
-  stream: Mutex<Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>> + Send + Sync>>>,
-  file: Mutex<Vec<u8>>,
-  path: String,
-  content_length: Option<u64>,
+/// Whether a `Range` header asks for a part starting at the beginning of the file.
+fn from_start(range: &str) -> bool {
+  matches!(byte_range(Some(range), u64::MAX), ByteRange::Partial(0, _))
 }
 
-impl Stream for StreamCache {
-  type Item = Result<Bytes, reqwest::Error>;
-  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-    match self.stream.lock().as_mut().poll_next(cx) {
-      Poll::Ready(Some(Ok(val))) => {
-        let mut file = self.file.lock();
-        if file.len() < FILESIZE_LIMIT {
-          file.extend_from_slice(&*val);
+/// `len` bytes of `body` from `start`.
+fn slice(
+  body: impl Stream<Item = io::Result<Bytes>> + Send,
+  start: u64,
+  len: u64,
+) -> impl Stream<Item = io::Result<Bytes>> + Send {
+  async_stream::stream! {
+    let mut body = Box::pin(body);
+    let (mut skip, mut left) = (start, len);
+    while left > 0 {
+      let mut chunk = match body.next().await {
+        Some(Ok(chunk)) => chunk,
+        Some(Err(e)) => {
+          yield Err(e);
+          break;
         }
-
-        Poll::Ready(Some(Ok(val)))
+        None => break,
+      };
+      if skip >= chunk.len() as u64 {
+        skip -= chunk.len() as u64;
+        continue;
       }
-      v => v,
+      chunk = chunk.slice(skip as usize..);
+      skip = 0;
+      let take = left.min(chunk.len() as u64);
+      left -= take;
+      yield Ok(chunk.slice(..take as usize));
+    }
+  }
+}
+
+/// Caches a file that's only been asked for in pieces so far, so later pieces are hits.
+fn fill_behind(route: &'static ProxyRoute, key: &str, response: &Response<Body>) {
+  let size = response
+    .headers()
+    .get(CONTENT_RANGE)
+    .and_then(|h| h.to_str().ok())
+    .and_then(|range| range.rsplit_once('/'))
+    .and_then(|(_, size)| size.parse().ok());
+  let cache_key = format!("{}/{key}", route.bucket);
+  if response.status() != StatusCode::PARTIAL_CONTENT
+    || !size.is_some_and(cache::fits)
+    || FLIGHTS.contains(&cache_key)
+  {
+    return;
+  }
+
+  let key = key.to_owned();
+  tokio::spawn(async move {
+    if let Err(status) = warm_route(route, &key).await {
+      warn!("Couldn't cache {cache_key} behind a range: {status}");
     }
+  });
+}
+
+/// What the origin said before the body, shared by every request waiting on a fetch.
+#[derive(Clone)]
+struct Origin {
+  status: StatusCode,
+  headers: Vec<(HeaderName, HeaderValue)>,
+  validators: Validators,
+  /// The whole file's, when the origin sent all of it and said how big it is.
+  size: Option<u64>,
+}
+
+/// An `Origin`, or the status and message to answer with instead.
+type Fetched = Result<Origin, (StatusCode, &'static str)>;
+
+impl Origin {
+  fn read(response: &reqwest::Response) -> Fetched {
+    let status = match upstream_status(response.status()) {
+      Ok(status) => status,
+      Err(status) => {
+        warn!(
+          "Storage answered {} for {}",
+          response.status(),
+          response.url()
+        );
+        let message = match status {
+          StatusCode::NOT_FOUND => "Nothing here.",
+          StatusCode::FORBIDDEN => "That's not for you.",
+          StatusCode::RANGE_NOT_SATISFIABLE => "That range isn't in the file.",
+          _ => "Storage is having a bad day.",
+        };
+        return Err((status, message));
+      }
+    };
+
+    Ok(Self {
+      status,
+      headers: PASSTHROUGH_HEADERS
+        .iter()
+        .filter_map(|name| {
+          let value = response.headers().get(name)?;
+          Some((name.clone(), value.clone()))
+        })
+        .collect(),
+      validators: Validators::from_origin(response.headers()),
+      size: (status == StatusCode::OK)
+        .then(|| response.content_length())
+        .flatten(),
+    })
   }
 
-  fn size_hint(&self) -> (usize, Option<usize>) {
-    self.stream.lock().size_hint()
+  /// The same file, answering for bytes `start` to `end` of it.
+  fn partial(mut self, start: u64, end: u64) -> Self {
+    self.status = StatusCode::PARTIAL_CONTENT;
+    self
+      .headers
+      .retain(|(name, _)| name != CONTENT_LENGTH && name != CONTENT_RANGE);
+    let size = self.size.unwrap_or_default();
+    self.headers.extend([
+      (CONTENT_LENGTH, HeaderValue::from(end + 1 - start)),
+      (
+        CONTENT_RANGE,
+        HeaderValue::from_str(&format!("bytes {start}-{end}/{size}")).unwrap(),
+      ),
+    ]);
+    self
+  }
+
+  fn respond(self, content_type: &str, cache_control: Option<&str>, body: Body) -> Response<Body> {
+    let mut builder = file_headers(&self.validators, cache_control)
+      .status(self.status)
+      .header(CONTENT_TYPE, content_type);
+    for (name, value) in self.headers {
+      builder = builder.header(name, value);
+    }
+    builder.body(body).unwrap()
   }
 }
 
-impl Drop for StreamCache {
-  fn drop(&mut self) {
-    let file = std::mem::replace(&mut *self.file.lock(), vec![]);
+/// Fetches a whole file for everyone who asks for it while it's on the way, and caches
+/// it once. Keeps going if they all leave and it's being cached, so the next one gets
+/// a hit, and stops if it isn't.
+async fn fetch_shared(url: String, cache_key: String, mut flight: flight::Sender<Fetched>) {
+  let response = match CLIENT.get(&url).send().await {
+    Ok(response) => response,
+    Err(e) => {
+      error!("Couldn't reach storage for {url}: {e}");
+      flight.head(Err((StatusCode::BAD_GATEWAY, "Couldn't reach storage.")));
+      return;
+    }
+  };
 
-    if let Some(content_length) = self.content_length {
-      if content_length == file.len() as u64 && file.len() < FILESIZE_LIMIT {
-        info!("Cached: {}: {}", &self.path, file.len());
-        let mut cache = FILE_CACHE.write();
-        cache.1.push_front(self.path.clone());
-        cache.0.entry(self.path.clone()).or_insert(file);
+  let origin = Origin::read(&response);
+  flight.head(origin.clone());
+  let Ok(origin) = origin else {
+    return;
+  };
 
-        if cache.1.len() > FILE_COUNT_LIMIT {
-          let key = cache.1.pop_back().unwrap();
-          cache.0.remove(&key);
+  let mut filler = match origin.status {
+    StatusCode::OK => cache::fill(&cache_key, origin.validators, response.content_length()),
+    _ => None,
+  };
+  let mut stream = response.bytes_stream();
+  while let Some(chunk) = stream.next().await {
+    match chunk {
+      Ok(chunk) => {
+        if let Some(filler) = &mut filler {
+          filler.push(&chunk);
+        } else if flight.abandoned() {
+          info!("Nobody wants {url} any more, stopping.");
+          return;
         }
+        flight.push(chunk).await;
+      }
+      Err(e) => {
+        warn!("Lost {url} halfway: {e}");
+        return;
       }
     }
   }
+
+  if let Some(filler) = filler {
+    filler.finish();
+  }
+  flight.finish();
+}
+
+/// Passes a range request straight on to the origin, unshared.
+async fn fetch_range(
+  url: &str,
+  content_type: &str,
+  cache_control: Option<&str>,
+  headers: &HeaderMap,
+) -> Response<Body> {
+  let mut request = CLIENT.get(url);
+  for name in [RANGE, IF_RANGE, IF_NONE_MATCH, IF_MODIFIED_SINCE] {
+    if let Some(value) = headers.get(&name) {
+      request = request.header(name, value);
+    }
+  }
+  let response = match request.send().await {
+    Ok(response) => response,
+    Err(e) => {
+      error!("Couldn't reach storage for {url}: {e}");
+      return plain(StatusCode::BAD_GATEWAY, "Couldn't reach storage.");
+    }
+  };
+
+  let validators = Validators::from_origin(response.headers());
+  // B2 ignores some conditionals, so check them here too
+  if response.status() == StatusCode::NOT_MODIFIED
+    || (response.status().is_success() && validators.not_modified(headers))
+  {
+    return not_modified(&validators, cache_control);
+  }
+
+  let origin = match Origin::read(&response) {
+    Ok(origin) => origin,
+    Err((status, message)) => return plain(status, message),
+  };
+  let body = response.bytes_stream().inspect(|chunk| {
+    if let Ok(chunk) = chunk {
+      stats::incr(&stats::BYTES_SERVED, chunk.len() as u64);
+    }
+  });
+  origin.respond(content_type, cache_control, Body::wrap_stream(body))
+}
+
+fn not_modified(validators: &Validators, cache_control: Option<&str>) -> Response<Body> {
+  file_headers(validators, cache_control)
+    .status(StatusCode::NOT_MODIFIED)
+    .body(Body::empty())
+    .unwrap()
+}
+
+/// What to answer with for an origin status. Errors don't carry the origin's body,
+/// B2 explains itself in XML nobody asked for.
+fn upstream_status(status: StatusCode) -> Result<StatusCode, StatusCode> {
+  match status {
+    StatusCode::OK | StatusCode::PARTIAL_CONTENT => Ok(status),
+    StatusCode::RANGE_NOT_SATISFIABLE => Err(status),
+    StatusCode::NOT_FOUND => Err(StatusCode::NOT_FOUND),
+    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(StatusCode::FORBIDDEN),
+    _ => Err(StatusCode::BAD_GATEWAY),
+  }
+}
+
+fn plain(status: StatusCode, message: &'static str) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+    .body(Body::from(message))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn byte_ranges() {
+    use ByteRange::*;
+    let range = |header: &str, len| byte_range(Some(header), len);
+
+    assert_eq!(byte_range(None, 100), Whole);
+    assert_eq!(range("bytes=0-", 100), Partial(0, 99));
+    assert_eq!(range("bytes=10-19", 100), Partial(10, 19));
+    assert_eq!(range("bytes=90-500", 100), Partial(90, 99));
+    // suffixes
+    assert_eq!(range("bytes=-10", 100), Partial(90, 99));
+    assert_eq!(range("bytes=-500", 100), Partial(0, 99));
+    assert_eq!(range("bytes=-0", 100), Unsatisfiable);
+    // nothing to give
+    assert_eq!(range("bytes=0-", 0), Unsatisfiable);
+    assert_eq!(range("bytes=-5", 0), Unsatisfiable);
+    assert_eq!(range("bytes=100-", 100), Unsatisfiable);
+    assert_eq!(range("bytes=150-200", 100), Unsatisfiable);
+    // ignored, the whole body is a fine answer to these
+    assert_eq!(range("bytes=20-10", 100), Whole);
+    assert_eq!(range("bytes=0-1,5-6", 100), Whole);
+    assert_eq!(range("bytes=abc", 100), Whole);
+    assert_eq!(range("items=0-5", 100), Whole);
+  }
+
+  fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+    pairs
+      .iter()
+      .map(|(name, value)| {
+        (
+          HeaderName::from_bytes(name.as_bytes()).unwrap(),
+          HeaderValue::from_str(value).unwrap(),
+        )
+      })
+      .collect()
+  }
+
+  const MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+  fn validators() -> Validators {
+    Validators {
+      etag: Some("\"abc\"".to_owned()),
+      last_modified: Some(MODIFIED.to_owned()),
+    }
+  }
+
+  #[test]
+  fn validators_fall_back_to_what_b2_sends() {
+    let b2 = Validators::from_origin(&headers(&[
+      ("x-bz-content-sha1", "unverified:abc"),
+      ("x-bz-upload-timestamp", "1445412480000"),
+    ]));
+    assert_eq!(b2.etag.as_deref(), Some("\"abc\""));
+    assert_eq!(b2.last_modified.as_deref(), Some(MODIFIED));
+
+    let parts = Validators::from_origin(&headers(&[("x-bz-content-sha1", "none")]));
+    assert_eq!(parts.etag, None);
+    assert_eq!(parts.last_modified, None);
+
+    let both = Validators::from_origin(&headers(&[
+      ("etag", "\"real\""),
+      ("x-bz-content-sha1", "abc"),
+    ]));
+    assert_eq!(both.etag.as_deref(), Some("\"real\""));
+  }
+
+  #[test]
+  fn if_none_match() {
+    let fresh = |tags| validators().not_modified(&headers(&[("if-none-match", tags)]));
+    assert!(fresh("\"abc\""));
+    assert!(fresh("W/\"abc\""), "weak tags match for If-None-Match");
+    assert!(fresh("\"xyz\", \"abc\""));
+    assert!(fresh("*"));
+    assert!(!fresh("\"xyz\""));
+
+    let untagged = Validators {
+      etag: None,
+      ..validators()
+    };
+    assert!(!untagged.not_modified(&headers(&[("if-none-match", "*")])));
+  }
+
+  #[test]
+  fn if_modified_since() {
+    let fresh = |since| validators().not_modified(&headers(&[("if-modified-since", since)]));
+    assert!(fresh(MODIFIED));
+    assert!(fresh("Thu, 22 Oct 2015 07:28:00 GMT"));
+    assert!(!fresh("Tue, 20 Oct 2015 07:28:00 GMT"));
+    assert!(!fresh("yesterday"));
+  }
+
+  #[test]
+  fn if_none_match_wins_over_if_modified_since() {
+    let request = headers(&[
+      ("if-none-match", "\"xyz\""),
+      ("if-modified-since", "Thu, 22 Oct 2015 07:28:00 GMT"),
+    ]);
+    assert!(!validators().not_modified(&request));
+  }
+
+  #[test]
+  fn if_range() {
+    let applies = |if_range| validators().range_applies(&headers(&[("if-range", if_range)]));
+    assert!(validators().range_applies(&HeaderMap::new()));
+    assert!(applies("\"abc\""));
+    assert!(!applies("\"xyz\""));
+    assert!(!applies("W/\"abc\""), "weak tags never match for If-Range");
+    assert!(applies(MODIFIED));
+    assert!(!applies("Thu, 22 Oct 2015 07:28:00 GMT"));
+  }
+
+  #[test]
+  fn secrets() {
+    assert!(same_secret("hunter2", "hunter2"));
+    assert!(!same_secret("hunter3", "hunter2"));
+    assert!(!same_secret("hunter", "hunter2"));
+    assert!(!same_secret("", "hunter2"));
+  }
+
+  #[test]
+  fn galleries_start_at_the_root() {
+    let mut http = HttpConfig::default();
+    http.routes.push(ProxyRoute {
+      hosts: vec!["pics.example.com".to_owned()],
+      bucket: "pics".to_owned(),
+      rewrite: "public/".to_owned(),
+      index: true,
+      ..Default::default()
+    });
+    let gallery = |host, path| {
+      let (route, dir) = gallery_for(&http, Some(host), path)?;
+      Some((route.bucket.as_str(), route.key(&dir)))
+    };
+
+    assert_eq!(
+      gallery("pics.example.com", "/"),
+      Some(("pics", "public/".to_owned()))
+    );
+    assert_eq!(
+      gallery("pics.example.com", "/2024/trip%20a/"),
+      Some(("pics", "public/2024/trip a/".to_owned()))
+    );
+    assert_eq!(gallery("pics.example.com", "/%2e%2e/"), None);
+    assert_eq!(gallery("pics.example.com", "/a//"), None);
+    // the default routes don't have galleries
+    assert_eq!(gallery("kota.is", "/"), None);
+  }
+
+  #[test]
+  fn normalized_paths() {
+    assert_eq!(normalize("abcde.png").as_deref(), Some("abcde.png"));
+    assert_eq!(
+      normalize("albums/2024/cat.png").as_deref(),
+      Some("albums/2024/cat.png")
+    );
+    assert_eq!(normalize("my%20cat.png").as_deref(), Some("my cat.png"));
+    assert_eq!(normalize("caf%C3%A9/a.png").as_deref(), Some("café/a.png"));
+    // climbing out, however it's spelled
+    for path in [
+      "..",
+      "../secret",
+      "a/../../secret",
+      "./a.png",
+      "%2e%2e/secret",
+      "%2E%2E/secret",
+      "a%2F..%2F..%2Fsecret",
+      "..\\secret",
+      "a%5c..%5csecret",
+    ] {
+      assert_eq!(normalize(path), None, "{path}");
+    }
+    // empty segments
+    for path in ["", "/a.png", "a//b.png", "a/"] {
+      assert_eq!(normalize(path), None, "{path}");
+    }
+    // not text, or not printable
+    for path in ["%ff.png", "a%c3.png", "a%00.png", "a%0a.png", "a%7f.png"] {
+      assert_eq!(normalize(path), None, "{path}");
+    }
+  }
+
+  #[test]
+  fn ranges_from_the_start() {
+    assert!(from_start("bytes=0-"));
+    assert!(from_start("bytes=0-1"));
+    assert!(!from_start("bytes=1-"));
+    assert!(!from_start("bytes=-100"));
+    assert!(!from_start("bytes=0-1,5-6"));
+  }
+
+  #[tokio::test]
+  async fn slices_across_chunks() {
+    let sliced = |start, len| {
+      let chunks = ["hello ", "there ", "world"].map(|c| io::Result::Ok(Bytes::from(c)));
+      slice(futures::stream::iter(chunks), start, len)
+        .map(|chunk| chunk.unwrap())
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(sliced(0, u64::MAX).await.concat(), b"hello there world");
+    assert_eq!(sliced(0, 2).await.concat(), b"he");
+    assert_eq!(sliced(4, 5).await.concat(), b"o the");
+    assert_eq!(sliced(12, 100).await.concat(), b"world");
+  }
 }
@@ -0,0 +1,151 @@
+use crate::config;
+use crate::index::{self, Entry};
+use anyhow::{bail, Result};
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// Give up rather than loop forever on a tiny alphabet.
+const MAX_TRIES: usize = 32;
+/// Random ids grow by a character after this many collisions in a row.
+const TRIES_PER_LENGTH: usize = 4;
+
+pub struct Claimed {
+  pub key: String,
+  /// The same contents are already uploaded under this key.
+  pub existing: bool,
+}
+
+/// Picks a key for hosting `file` in `bucket` that nothing else has, and claims it
+/// in the index so a concurrent upload can't pick it too.
+///
+/// With `content_hash` on, the id comes from the file's sha256. The same file always
+/// gets the same key, and a different file whose hash starts the same gets a longer one.
+pub async fn claim(
+  bucket: &str,
+  file: &Path,
+  extension: &str,
+  expires: Option<u64>,
+) -> Result<Claimed> {
+  let conf = &config::get().ids;
+  let alphabet: Vec<char> = conf.alphabet.chars().collect();
+  if alphabet.len() < 2 || conf.length == 0 {
+    bail!("ids need a length and at least two characters to pick from");
+  }
+
+  let hash = match conf.content_hash {
+    true => Some(hash_file(file).await?),
+    false => None,
+  };
+
+  for attempt in 0..MAX_TRIES {
+    let id = match &hash {
+      Some(hash) => encode(hash, &alphabet, conf.length + attempt),
+      None => random(&alphabet, conf.length + attempt / TRIES_PER_LENGTH),
+    };
+    let key = match extension {
+      "" => id,
+      extension => format!("{id}.{extension}"),
+    };
+
+    if let Some(existing) = index::get(bucket, &key) {
+      // the very same file, its link can be shared. Deleted ids stay taken.
+      if hash.is_some() && !existing.deleted && existing.hash == hash.as_ref().map(hex) {
+        index::keep_until(bucket, &key, expires);
+        return Ok(Claimed {
+          key,
+          existing: true,
+        });
+      }
+      continue;
+    }
+    if conf.check_storage && crate::backblaze::exists(bucket, &key).await? {
+      warn!("{bucket}/{key} exists but isn't in the index.");
+      continue;
+    }
+
+    let entry = Entry {
+      bucket: bucket.to_owned(),
+      key: key.clone(),
+      hash: hash.as_ref().map(hex),
+      created: index::now(),
+      expires,
+      ..Default::default()
+    };
+    if index::claim(entry) {
+      return Ok(Claimed {
+        key,
+        existing: false,
+      });
+    }
+  }
+
+  bail!("Couldn't find a free id in {bucket} after {MAX_TRIES} tries")
+}
+
+fn random(alphabet: &[char], length: usize) -> String {
+  let mut rng = thread_rng();
+  (0..length)
+    .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+    .collect()
+}
+
+/// The first `length` digits of `hash` written in base `alphabet.len()`. Past the
+/// digits `hash` has, more come from hashing it again with a counter, so every length
+/// gives a different id.
+fn encode(hash: &[u8; 32], alphabet: &[char], length: usize) -> String {
+  let base = alphabet.len() as u128;
+  let mut digits = Vec::with_capacity(length);
+  let mut block = *hash;
+  for counter in 0u64.. {
+    let halves =
+      [&block[..16], &block[16..]].map(|half| u128::from_be_bytes(half.try_into().unwrap()));
+    for mut n in halves {
+      // a u128 has at least 16 digits in any base up to 256
+      for _ in 0..16 {
+        digits.push(alphabet[(n % base) as usize]);
+        n /= base;
+      }
+    }
+    if digits.len() >= length {
+      break;
+    }
+    block = Sha256::new()
+      .chain_update(hash)
+      .chain_update(counter.to_be_bytes())
+      .finalize()
+      .into();
+  }
+  digits.truncate(length);
+  digits.into_iter().collect()
+}
+
+async fn hash_file(file: &Path) -> Result<[u8; 32]> {
+  let contents = tokio::fs::read(file).await?;
+  let hash = tokio::task::spawn_blocking(move || Sha256::digest(&contents)).await?;
+  Ok(hash.into())
+}
+
+fn hex(hash: &[u8; 32]) -> String {
+  hash.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn content_ids_differ_at_every_length() {
+    let hash = Sha256::digest(b"smee").into();
+    let alphabet: Vec<char> = "ab".chars().collect();
+    let ids: Vec<_> = (1..=MAX_TRIES + 64)
+      .map(|length| encode(&hash, &alphabet, length))
+      .collect();
+    for (i, id) in ids.iter().enumerate() {
+      assert_eq!(id.chars().count(), i + 1);
+    }
+    // longer ids start with the shorter ones, the same file keeps its key
+    assert!(ids[40].starts_with(&ids[20]));
+    assert_eq!(encode(&hash, &alphabet, 40), ids[39]);
+  }
+}
@@ -0,0 +1,47 @@
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// What `/image` can re-encode pictures to. `original` leaves them alone.
+pub const FORMATS: &[&str] = &["original", "webp", "avif"];
+
+/// Formats worth re-encoding. Gifs and anything else animated stay as they are.
+const STILL_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp", "avif", "bmp", "tiff"];
+
+pub fn is_still(path: &Path) -> bool {
+  path
+    .extension()
+    .and_then(|ext| ext.to_str())
+    .is_some_and(|ext| STILL_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Re-encodes a still image with ffmpeg, returning the new file next to the old one.
+pub async fn reencode(path: &Path, format: &str) -> Result<PathBuf> {
+  let codec_args: &[&str] = match format {
+    "webp" => &["-c:v", "libwebp", "-quality", "85"],
+    "avif" => &["-c:v", "libaom-av1", "-still-picture", "1", "-crf", "30"],
+    _ => bail!("Can't encode to {format}"),
+  };
+
+  if path.extension().is_some_and(|ext| ext == format) {
+    return Ok(path.to_owned());
+  }
+  let out = path.with_extension(format);
+
+  let output = Command::new("ffmpeg")
+    .args(["-y", "-loglevel", "error", "-i"])
+    .arg(path)
+    .args(codec_args)
+    .arg(&out)
+    .output()
+    .await?;
+  if !output.status.success() {
+    bail!(
+      "ffmpeg failed on {}: {}",
+      path.display(),
+      String::from_utf8_lossy(&output.stderr)
+    );
+  }
+
+  Ok(out)
+}
@@ -0,0 +1,209 @@
+use crate::store::Store;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+  cmp::Reverse,
+  collections::HashMap,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+const INDEX_FILE: &str = "index.json";
+
+lazy_static! {
+  /// Every object we've put in a bucket, keyed by `<bucket>/<key>`.
+  static ref INDEX: Store<HashMap<String, Entry>> = Store::open(INDEX_FILE);
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Entry {
+  pub bucket: String,
+  pub key: String,
+  /// Hex sha256 of the contents, when ids are content addressed.
+  pub hash: Option<String>,
+  /// Unix seconds.
+  pub created: u64,
+  /// Unix seconds after which the object is gone. `None` keeps it forever.
+  pub expires: Option<u64>,
+  /// Telegram user id of whoever asked for it first.
+  pub uploader: Option<u64>,
+  /// Everybody who uploaded the same contents after that, when ids are content addressed.
+  pub also_uploaded_by: Vec<u64>,
+  /// The url it was mirrored from. Files sent through telegram have none.
+  pub source: Option<String>,
+  pub title: Option<String>,
+  /// Bytes.
+  pub size: u64,
+  /// Deleted from the bucket. The entry stays so its id is never handed out again,
+  /// as caches may hold on to the old file under it for a long time.
+  pub deleted: bool,
+}
+
+impl Entry {
+  pub fn is_expired(&self, now: u64) -> bool {
+    self.expires.is_some_and(|expires| expires <= now)
+  }
+
+  pub fn uploaders(&self) -> impl Iterator<Item = u64> + '_ {
+    self.uploader.iter().chain(&self.also_uploaded_by).copied()
+  }
+
+  pub fn uploaded_by(&self, user: u64) -> bool {
+    self.uploaders().any(|uploader| uploader == user)
+  }
+
+  /// Records somebody uploading the same contents again.
+  pub fn add_uploader(&mut self, user: u64) {
+    if self.uploader.is_none() {
+      self.uploader = Some(user);
+    } else if !self.uploaded_by(user) {
+      self.also_uploaded_by.push(user);
+    }
+  }
+
+  /// Takes `user` off the uploaders. Returns whether anybody is left.
+  pub fn remove_uploader(&mut self, user: u64) -> bool {
+    self.also_uploaded_by.retain(|&uploader| uploader != user);
+    if self.uploader == Some(user) {
+      self.uploader = match self.also_uploaded_by.is_empty() {
+        true => None,
+        false => Some(self.also_uploaded_by.remove(0)),
+      };
+    }
+    self.uploader.is_some()
+  }
+}
+
+fn index_key(bucket: &str, key: &str) -> String {
+  format!("{bucket}/{key}")
+}
+
+/// A short stand-in for `<bucket>/<key>`, for places without room for the whole
+/// thing, like the 64 bytes telegram allows in button data.
+pub fn handle(bucket: &str, key: &str) -> String {
+  let hash = Sha256::digest(index_key(bucket, key));
+  hash[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn by_handle(handle: &str) -> Option<Entry> {
+  INDEX
+    .read()
+    .values()
+    .find(|entry| !entry.deleted && self::handle(&entry.bucket, &entry.key) == handle)
+    .cloned()
+}
+
+/// The entry for `key`, deleted or not.
+pub fn get(bucket: &str, key: &str) -> Option<Entry> {
+  INDEX.read().get(&index_key(bucket, key)).cloned()
+}
+
+pub fn update(bucket: &str, key: &str, f: impl FnOnce(&mut Entry)) {
+  INDEX.update(|index| {
+    if let Some(entry) = index.get_mut(&index_key(bucket, key)) {
+      f(entry);
+    }
+  })
+}
+
+/// Entries that haven't been deleted matching `filter`, newest first.
+pub fn list(filter: impl Fn(&Entry) -> bool) -> Vec<Entry> {
+  let mut entries: Vec<Entry> = INDEX
+    .read()
+    .values()
+    .filter(|e| !e.deleted && filter(e))
+    .cloned()
+    .collect();
+  entries.sort_by_key(|entry| Reverse(entry.created));
+  entries
+}
+
+/// Records `entry`, unless its key is already taken. Returns whether it was recorded.
+pub fn claim(entry: Entry) -> bool {
+  INDEX.update(|index| {
+    let key = index_key(&entry.bucket, &entry.key);
+    if index.contains_key(&key) {
+      return false;
+    }
+    index.insert(key, entry);
+    true
+  })
+}
+
+/// Pushes an object's expiry back to `expires`, never forward. Shared links
+/// shouldn't vanish because someone else uploaded the same file with a ttl.
+pub fn keep_until(bucket: &str, key: &str, expires: Option<u64>) {
+  INDEX.update(|index| {
+    if let Some(entry) = index.get_mut(&index_key(bucket, key)) {
+      entry.expires = match (entry.expires, expires) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        _ => None,
+      };
+    }
+  })
+}
+
+pub fn is_expired(bucket: &str, key: &str) -> bool {
+  INDEX
+    .read()
+    .get(&index_key(bucket, key))
+    .is_some_and(|entry| entry.is_expired(now()))
+}
+
+pub fn expired() -> Vec<Entry> {
+  let now = now();
+  INDEX
+    .read()
+    .values()
+    .filter(|entry| !entry.deleted && entry.is_expired(now))
+    .cloned()
+    .collect()
+}
+
+/// Marks an object as deleted from its bucket, keeping its id taken.
+pub fn bury(bucket: &str, key: &str) {
+  update(bucket, key, |entry| entry.deleted = true)
+}
+
+/// Forgets an object entirely, for ids claimed by uploads that never happened.
+pub fn remove(bucket: &str, key: &str) -> Option<Entry> {
+  INDEX.update(|index| index.remove(&index_key(bucket, key)))
+}
+
+pub fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn shared_uploads_keep_every_uploader() {
+    let mut entry = Entry {
+      uploader: Some(1),
+      ..Default::default()
+    };
+    entry.add_uploader(2);
+    entry.add_uploader(1);
+    entry.add_uploader(3);
+    assert_eq!(entry.uploaders().collect::<Vec<_>>(), [1, 2, 3]);
+    assert!(entry.uploaded_by(2) && !entry.uploaded_by(4));
+
+    // the next one in line takes over
+    assert!(entry.remove_uploader(1));
+    assert_eq!(entry.uploader, Some(2));
+    assert!(entry.remove_uploader(3));
+    assert!(!entry.remove_uploader(2));
+    assert_eq!(entry.uploaders().count(), 0);
+
+    let mut unowned = Entry::default();
+    unowned.add_uploader(5);
+    assert_eq!(unowned.uploader, Some(5));
+    assert!(unowned.also_uploaded_by.is_empty());
+  }
+}
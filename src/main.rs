@@ -3,20 +3,45 @@ extern crate pretty_env_logger;
 extern crate log;
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 use tokio::join;
 
+mod auth;
 mod backblaze;
+mod cache;
 mod cert;
+mod config;
+mod cookies;
+mod download;
+mod error;
+mod flight;
+mod gallery;
 mod http;
+mod ids;
+mod image;
+mod index;
+mod messages;
 mod music;
+mod reaper;
+mod settings;
 mod smee;
+mod stats;
+mod store;
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
   pretty_env_logger::init();
 
   let mut args = Args::parse();
+  config::load(&args.config)?;
+
+  if let Some(Cmd::Storage {
+    cmd: StorageCmd::Ls(ls),
+  }) = args.cmd
+  {
+    return storage_ls(ls).await;
+  }
 
   if args.cert {
     // override the port
@@ -26,7 +51,11 @@ pub async fn main() -> Result<()> {
     });
   }
 
-  let _ = join!(smee::start(), http::serve(args.port));
+  if let Err(err) = cache::load().await {
+    error!("Couldn't load the disk cache: {err:?}");
+  }
+
+  let _ = join!(smee::start(), http::serve(args.port), reaper::run());
 
   Ok(())
 }
@@ -39,4 +68,113 @@ struct Args {
 
   #[arg(short, long)]
   cert: bool,
+
+  #[arg(long, default_value = "smee.toml")]
+  config: PathBuf,
+
+  #[command(subcommand)]
+  cmd: Option<Cmd>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+  /// Look at what's been hosted instead of starting the bot.
+  Storage {
+    #[command(subcommand)]
+    cmd: StorageCmd,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+enum StorageCmd {
+  /// List hosted files, newest first.
+  Ls(LsArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct LsArgs {
+  /// Only this bucket (i-kota, v-kota).
+  #[arg(short, long)]
+  bucket: Option<String>,
+
+  /// Only files this telegram user asked for. With `--remote`, only those the index
+  /// knows they uploaded.
+  #[arg(short, long)]
+  user: Option<u64>,
+
+  /// Only files whose key, title or source contain this.
+  #[arg(short, long)]
+  search: Option<String>,
+
+  /// List what's actually in B2 rather than the local index.
+  #[arg(long)]
+  remote: bool,
+}
+
+async fn storage_ls(args: LsArgs) -> Result<()> {
+  if args.remote {
+    let buckets = match &args.bucket {
+      Some(bucket) => vec![bucket.as_str()],
+      None => vec!["i-kota", "v-kota"],
+    };
+    for bucket in buckets {
+      for object in backblaze::list(bucket, "").await? {
+        let entry = index::get(bucket, &object.key).filter(|entry| !entry.deleted);
+        let indexed = entry.is_some();
+        // who uploaded what is only in the index
+        let other_user = args
+          .user
+          .is_some_and(|user| !entry.is_some_and(|entry| entry.uploaded_by(user)));
+        if other_user
+          || args
+            .search
+            .as_ref()
+            .is_some_and(|s| !object.key.contains(s.as_str()))
+        {
+          continue;
+        }
+        println!(
+          "{bucket}/{}\t{}\t{}\t{}",
+          object.key,
+          object.size,
+          object.last_modified,
+          if indexed { "indexed" } else { "unindexed" }
+        );
+      }
+    }
+    return Ok(());
+  }
+
+  let entries = index::list(|entry| {
+    let text = |field: &Option<String>| field.as_deref().unwrap_or_default().to_owned();
+    args
+      .bucket
+      .as_ref()
+      .is_none_or(|bucket| &entry.bucket == bucket)
+      && args.user.is_none_or(|user| entry.uploaded_by(user))
+      && args.search.as_ref().is_none_or(|search| {
+        [entry.key.clone(), text(&entry.title), text(&entry.source)]
+          .iter()
+          .any(|field| field.contains(search.as_str()))
+      })
+  });
+
+  for entry in entries {
+    println!(
+      "{}/{}\t{}\t{}\t{}\t{}\t{}",
+      entry.bucket,
+      entry.key,
+      entry.size,
+      entry.created,
+      entry
+        .uploaders()
+        .map(|u| u.to_string())
+        .collect::<Vec<_>>()
+        .join(","),
+      entry.title.unwrap_or_default(),
+      entry.source.unwrap_or_default()
+    );
+  }
+
+  Ok(())
 }
@@ -0,0 +1,329 @@
+use crate::error::DownloadError;
+use crate::settings::ChatSettings;
+use std::fmt::{self, Display};
+
+pub const LANGUAGES: &[&str] = &["en", "es"];
+pub const PERSONAS: &[&str] = &["pirate", "plain"];
+
+type Templates = &'static [(&'static str, &'static str)];
+
+/// Templates by (language, persona). `{name}` placeholders are filled in with `Template::arg`.
+/// Lookups fall back to the plain persona of the same language, then to english,
+/// and english pirate has every key.
+const CATALOG: &[(&str, &str, Templates)] = &[
+  ("en", "pirate", EN_PIRATE),
+  ("en", "plain", EN_PLAIN),
+  ("es", "plain", ES_PLAIN),
+];
+
+const EN_PIRATE: Templates = &[
+  ("no_url", "Oh sir.. did you mean to give a url? I didn't get one."),
+  ("no_file", "Oh sir.. there's no file here to stow. Send me one or reply /host to one."),
+  ("host_start", "Aye, stowing that in the hold..."),
+  ("song_start", "Aye-aye cap'n! Let me ask the crew if they've heard of a song by the name \"{query}\""),
+  ("audio_start", "Oh sure, cap'n! I'll get that for you. ({limit}MB limit)"),
+  ("video_start", "Aye-aye cap'n! Downloading video with a {limit}MB filesize limit."),
+  ("image_start", "Aye, I'll fetch those pictures for ye!"),
+  ("image_uploading", "Got 'em! Hanging them in the gallery..."),
+  ("too_large", "Oh Cap'n, this file is too large for Telegram. Let me host it for you!\n\nUploading..."),
+  ("hosted", "Here it is, Cap'n! {url}"),
+  ("hosted_ttl", "Here it is, Cap'n! {url}\nIt'll sink in {ttl}."),
+  ("ttl_not_hosted", "Mind ye, what I sent here won't sink, ttl= only works on hosted links."),
+  ("sending", "I got the file, sir! Sending it now..."),
+  ("no_title", "No Title Found"),
+  ("retrying", "Blast, that didn't work. Trying again {how} ({attempt}/{attempts})..."),
+  ("fallback_retry", "after a breather"),
+  ("fallback_no_cookies", "without cookies"),
+  ("fallback_lower_quality", "at a lower quality"),
+  ("fallback_alt_extractor", "through another door"),
+  ("error_route", "Oh my.. I don't know that route."),
+  ("error_option", "Oh my.. I don't understand one of those options."),
+  ("error_unsupported", "Oh my.. I don't know how to fetch things from there."),
+  ("error_download", "Oh my.. the crew couldn't fetch that one."),
+  ("error_geo", "Oh my.. that one's locked away in foreign waters."),
+  ("error_login", "Oh my.. they won't let me in without papers."),
+  ("error_too_large", "Oh my.. that's too heavy for the ship."),
+  ("error_storage", "Oh my.. I couldn't stow the file in the hold."),
+  ("error_telegram", "Oh my.. Telegram wouldn't take it."),
+  ("failed", "Oh my.. {err}"),
+  ("denied", "Apologies, but I'm only taking orders from the crew here."),
+  ("over_quota", "Sorry cap'n, you've used up today's ration ({jobs}/{max_jobs} jobs, {mb}/{max_mb}MB). Try me again tomorrow!"),
+  ("admin_only", "Only officers may give that order, sir."),
+  ("officers_only", "Only the chat's officers may change that, sir."),
+  ("allowed", "Welcome aboard!"),
+  ("denied_target", "They'll walk the plank, cap'n."),
+  ("acl_usage", "Usage: /allow|/deny <user|chat|admin> [id], or /allow|/deny everyone"),
+  ("quota_self", "Today you've used {jobs}/{max_jobs} jobs and {mb}/{max_mb}MB."),
+  ("quota_who", "I don't know who you are, sir."),
+  ("quota_admin_only", "Only officers may hand out rations, sir."),
+  ("quota_usage", "Usage: /quota <user id|default> <jobs> <MB>"),
+  ("quota_numbers", "Jobs and MB need to be numbers, sir."),
+  ("quota_bad_user", "{id} doesn't look like a user id."),
+  ("quota_set", "Aye, {who} gets {jobs} jobs and {mb}MB a day."),
+  ("mine_empty", "Ye haven't stowed anything with me yet."),
+  ("mine_header", "Yer treasure ({count} in the hold):"),
+  ("mine_not_yours", "That's not yer treasure to toss."),
+  ("mine_unlisted", "Struck {path} off yer list, the others who stowed it keep it."),
+  ("purged", "{path} has been sent to Davy Jones' locker."),
+  ("cache_cleared", "Swabbed the cache, {count} entries gone."),
+  ("cache_usage", "Usage: /cache clear"),
+  ("cert_days", "The certificate expires in {days} days."),
+  ("cert_none", "We don't have a certificate, cap'n."),
+  ("ytdlp_usage", "Usage: /ytdlp update"),
+  ("cookies_usage", "Usage: /cookies, /cookies set <domain> in reply to a cookies.txt, or /cookies rm <domain>"),
+  ("cookies_no_file", "Reply to a cookies file with that, cap'n."),
+  ("cookies_saved", "Stowed the cookies for {domain}: {state}"),
+  ("cookies_removed", "Tossed the cookies for {domain} overboard."),
+  ("cookies_none", "There be no cookies for {domain}."),
+  ("cookies_bad_domain", "{domain} doesn't look like any port I know."),
+  ("cookies_bad_file", "That's no cookies.txt I can read, cap'n."),
+  ("cookies_failed", "Something went wrong with them cookies, the logs know more."),
+  ("automirror_on", "Aye! I'll keep an eye out for links."),
+  ("automirror_off", "Very well, I'll only mirror when asked."),
+  ("automirror_status", "Auto-mirror is {state}. Usage: /automirror on|off"),
+  ("settings_menu", "Here's how we run this ship:"),
+  ("settings_updated", "Aye, {key} updated."),
+  ("settings_unknown", "There's no {key} aboard this ship."),
+  ("settings_not_an_option", "{value} won't do, pick one of: {options}"),
+  ("settings_bad_value", "{value} won't do for that."),
+  ("setting_size_limit", "Biggest haul: {value}MB"),
+  ("setting_container", "Video barrel: {value}"),
+  ("setting_resolution", "Spyglass: {value}"),
+  ("setting_audio_codec", "Shanty format: {value}"),
+  ("setting_caption", "Caption: {value}"),
+  ("setting_image_format", "Painting format: {value}"),
+  ("setting_image_reply", "Paintings as: {value}"),
+  ("setting_language", "Tongue: {value}"),
+  ("setting_persona", "Voice: {value}"),
+  ("setting_delete_status", "Toss status messages: {value}"),
+  ("setting_none", "none"),
+  ("settings_done", "Done"),
+  ("stats", "Since I last woke up:\nJobs run: {jobs}\nBytes served: {mb}MB\nCache hit rate: {rate}% ({hits} hits, {memory} from memory, {misses} misses)\n{dir}/ disk usage: {disk}MB"),
+];
+
+const EN_PLAIN: Templates = &[
+  ("no_url", "Please include a URL."),
+  ("no_file", "Send a file, or reply /host to one."),
+  ("host_start", "Uploading..."),
+  ("song_start", "Searching for a song named \"{query}\"..."),
+  ("audio_start", "Downloading audio ({limit}MB limit)..."),
+  ("video_start", "Downloading video ({limit}MB limit)..."),
+  ("image_start", "Downloading images..."),
+  ("image_uploading", "Uploading images..."),
+  (
+    "too_large",
+    "This file is too large for Telegram, uploading it instead...",
+  ),
+  ("hosted", "Done: {url}"),
+  ("hosted_ttl", "Done: {url}\nExpires in {ttl}."),
+  ("ttl_not_hosted", "Files sent here don't expire, ttl= only applies to hosted links."),
+  ("sending", "Downloaded, sending now..."),
+  ("no_title", "Untitled"),
+  (
+    "retrying",
+    "That didn't work, retrying {how} ({attempt}/{attempts})...",
+  ),
+  ("fallback_retry", "after a short wait"),
+  ("fallback_no_cookies", "without cookies"),
+  ("fallback_lower_quality", "at a lower quality"),
+  ("fallback_alt_extractor", "with a different extractor"),
+  ("error_route", "There's no route with that name."),
+  ("error_option", "One of those options isn't valid."),
+  ("error_unsupported", "This site isn't supported."),
+  ("error_download", "The download failed."),
+  ("error_geo", "This content isn't available in our region."),
+  ("error_login", "This content requires a login."),
+  ("error_too_large", "This file is over the size limit."),
+  ("error_storage", "The upload failed."),
+  ("error_telegram", "Telegram rejected the file."),
+  ("failed", "Failed: {err}"),
+  ("denied", "You're not allowed to use this bot."),
+  (
+    "over_quota",
+    "You've reached today's limit ({jobs}/{max_jobs} jobs, {mb}/{max_mb}MB). Try again tomorrow.",
+  ),
+  ("admin_only", "This command is for admins only."),
+  ("officers_only", "Only chat admins can change this."),
+  ("allowed", "Allowed."),
+  ("denied_target", "Denied."),
+  (
+    "quota_self",
+    "Today you've used {jobs}/{max_jobs} jobs and {mb}/{max_mb}MB.",
+  ),
+  ("quota_who", "Couldn't tell who sent this."),
+  ("quota_admin_only", "Only admins can set quotas."),
+  ("quota_numbers", "Jobs and MB must be numbers."),
+  ("quota_bad_user", "{id} isn't a valid user id."),
+  ("quota_set", "{who} now has {jobs} jobs and {mb}MB per day."),
+  ("mine_empty", "You haven't uploaded anything yet."),
+  ("mine_header", "Your uploads ({count} total):"),
+  ("mine_not_yours", "You can only delete your own uploads."),
+  ("mine_unlisted", "Removed {path} from your uploads. Others uploaded it too, so it stays up."),
+  ("purged", "Deleted {path}."),
+  ("cache_cleared", "Cache cleared, {count} entries removed."),
+  ("cert_days", "The certificate expires in {days} days."),
+  ("cert_none", "No certificate found."),
+  ("cookies_no_file", "Send that as a reply to a cookies file."),
+  ("cookies_saved", "Saved cookies for {domain}: {state}"),
+  ("cookies_removed", "Removed cookies for {domain}."),
+  ("cookies_none", "There are no cookies for {domain}."),
+  ("cookies_bad_domain", "{domain} doesn't look like a domain."),
+  ("cookies_bad_file", "That isn't a cookies.txt I can read."),
+  ("cookies_failed", "Couldn't handle those cookies, see the logs."),
+  ("automirror_on", "Auto-mirror enabled."),
+  ("automirror_off", "Auto-mirror disabled."),
+  ("settings_menu", "Settings:"),
+  ("settings_updated", "Updated {key}."),
+  ("settings_unknown", "There's no setting called {key}."),
+  ("settings_not_an_option", "{value} isn't one of: {options}"),
+  ("settings_bad_value", "{value} isn't a valid value for that."),
+  ("setting_size_limit", "Size limit: {value}MB"),
+  ("setting_container", "Video container: {value}"),
+  ("setting_resolution", "Resolution: {value}"),
+  ("setting_audio_codec", "Audio codec: {value}"),
+  ("setting_caption", "Caption: {value}"),
+  ("setting_image_format", "Image format: {value}"),
+  ("setting_image_reply", "Image replies: {value}"),
+  ("setting_language", "Language: {value}"),
+  ("setting_persona", "Persona: {value}"),
+  ("setting_delete_status", "Delete status messages: {value}"),
+  ("setting_none", "none"),
+  ("settings_done", "Done"),
+  ("stats", "Since startup:\nJobs run: {jobs}\nBytes served: {mb}MB\nCache hit rate: {rate}% ({hits} hits, {memory} from memory, {misses} misses)\n{dir}/ disk usage: {disk}MB"),
+];
+
+const ES_PLAIN: Templates = &[
+  ("no_url", "Por favor, incluye una URL."),
+  ("no_file", "Envía un archivo o responde /host a uno."),
+  ("host_start", "Subiendo..."),
+  ("song_start", "Buscando una canción llamada \"{query}\"..."),
+  ("audio_start", "Descargando audio (límite de {limit}MB)..."),
+  ("video_start", "Descargando video (límite de {limit}MB)..."),
+  ("image_start", "Descargando imágenes..."),
+  ("image_uploading", "Subiendo imágenes..."),
+  ("too_large", "El archivo es demasiado grande para Telegram, subiéndolo..."),
+  ("hosted", "Listo: {url}"),
+  ("hosted_ttl", "Listo: {url}\nCaduca en {ttl}."),
+  ("ttl_not_hosted", "Los archivos enviados aquí no caducan, ttl= solo afecta a los enlaces alojados."),
+  ("sending", "Descargado, enviando..."),
+  ("no_title", "Sin título"),
+  ("retrying", "No funcionó, reintentando {how} ({attempt}/{attempts})..."),
+  ("fallback_retry", "tras una breve espera"),
+  ("fallback_no_cookies", "sin cookies"),
+  ("fallback_lower_quality", "con menor calidad"),
+  ("fallback_alt_extractor", "con otro extractor"),
+  ("error_route", "No existe una ruta con ese nombre."),
+  ("error_option", "Una de esas opciones no es válida."),
+  ("error_unsupported", "Este sitio no es compatible."),
+  ("error_download", "La descarga falló."),
+  ("error_geo", "Este contenido no está disponible en nuestra región."),
+  ("error_login", "Este contenido requiere iniciar sesión."),
+  ("error_too_large", "El archivo supera el límite de tamaño."),
+  ("error_storage", "La subida falló."),
+  ("error_telegram", "Telegram rechazó el archivo."),
+  ("failed", "Error: {err}"),
+  ("denied", "No tienes permiso para usar este bot."),
+  ("over_quota", "Has alcanzado el límite de hoy ({jobs}/{max_jobs} trabajos, {mb}/{max_mb}MB). Inténtalo mañana."),
+  ("admin_only", "Este comando es solo para administradores."),
+  ("officers_only", "Solo los administradores del chat pueden cambiar esto."),
+  ("allowed", "Permitido."),
+  ("denied_target", "Denegado."),
+  ("quota_self", "Hoy has usado {jobs}/{max_jobs} trabajos y {mb}/{max_mb}MB."),
+  ("quota_who", "No sé quién envió esto."),
+  ("quota_admin_only", "Solo los administradores pueden asignar cuotas."),
+  ("quota_numbers", "Los trabajos y los MB deben ser números."),
+  ("quota_bad_user", "{id} no es un id de usuario válido."),
+  ("quota_set", "{who} ahora tiene {jobs} trabajos y {mb}MB por día."),
+  ("mine_empty", "Todavía no has subido nada."),
+  ("mine_header", "Tus archivos ({count} en total):"),
+  ("mine_not_yours", "Solo puedes borrar tus propios archivos."),
+  ("mine_unlisted", "{path} quitado de tus archivos. Otros también lo subieron, así que sigue disponible."),
+  ("purged", "{path} eliminado."),
+  ("cache_cleared", "Caché vaciada, {count} entradas eliminadas."),
+  ("cert_days", "El certificado vence en {days} días."),
+  ("cert_none", "No hay certificado."),
+  ("cookies_no_file", "Responde a un archivo de cookies con ese comando."),
+  ("cookies_saved", "Cookies de {domain} guardadas: {state}"),
+  ("cookies_removed", "Cookies de {domain} eliminadas."),
+  ("cookies_none", "No hay cookies de {domain}."),
+  ("cookies_bad_domain", "{domain} no parece un dominio."),
+  ("cookies_bad_file", "Ese no es un cookies.txt que pueda leer."),
+  ("cookies_failed", "No pude procesar esas cookies, revisa los registros."),
+  ("automirror_on", "Espejo automático activado."),
+  ("automirror_off", "Espejo automático desactivado."),
+  ("automirror_status", "El espejo automático está {state}. Uso: /automirror on|off"),
+  ("settings_menu", "Configuración:"),
+  ("settings_updated", "{key} actualizado."),
+  ("settings_unknown", "No existe la opción {key}."),
+  ("settings_not_an_option", "{value} no es una de: {options}"),
+  ("settings_bad_value", "{value} no es un valor válido para eso."),
+  ("setting_size_limit", "Tamaño máximo: {value}MB"),
+  ("setting_container", "Contenedor de video: {value}"),
+  ("setting_resolution", "Resolución: {value}"),
+  ("setting_audio_codec", "Códec de audio: {value}"),
+  ("setting_caption", "Descripción: {value}"),
+  ("setting_image_format", "Formato de imagen: {value}"),
+  ("setting_image_reply", "Respuestas de imágenes: {value}"),
+  ("setting_language", "Idioma: {value}"),
+  ("setting_persona", "Personalidad: {value}"),
+  ("setting_delete_status", "Borrar mensajes de estado: {value}"),
+  ("setting_none", "ninguna"),
+  ("settings_done", "Listo"),
+  ("stats", "Desde el arranque:\nTrabajos: {jobs}\nDatos servidos: {mb}MB\nAciertos de caché: {rate}% ({hits} aciertos, {memory} en memoria, {misses} fallos)\nUso de disco de {dir}/: {disk}MB"),
+];
+
+pub struct Template(String);
+
+impl Template {
+  pub fn arg(self, name: &str, value: impl Display) -> Self {
+    Self(self.0.replace(&format!("{{{name}}}"), &value.to_string()))
+  }
+}
+
+impl Display for Template {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl AsRef<str> for Template {
+  fn as_ref(&self) -> &str {
+    &self.0
+  }
+}
+
+impl From<Template> for String {
+  fn from(template: Template) -> Self {
+    template.0
+  }
+}
+
+/// Looks up the template for `key` in the chat's language and persona.
+pub fn t(settings: &ChatSettings, key: &str) -> Template {
+  let lookups = [
+    (settings.language.as_str(), settings.persona.as_str()),
+    (settings.language.as_str(), "plain"),
+    ("en", settings.persona.as_str()),
+    ("en", "pirate"),
+  ];
+
+  let template = lookups
+    .iter()
+    .find_map(|(language, persona)| lookup(language, persona, key))
+    .unwrap_or(key);
+
+  Template(template.to_owned())
+}
+
+fn lookup(language: &str, persona: &str, key: &str) -> Option<&'static str> {
+  let (_, _, templates) = CATALOG
+    .iter()
+    .find(|(l, p, _)| *l == language && *p == persona)?;
+  templates.iter().find(|(k, _)| *k == key).map(|(_, t)| *t)
+}
+
+/// What to tell the user about `err`. They never see our debug output, that goes to the log.
+pub fn error(settings: &ChatSettings, err: &DownloadError) -> Template {
+  log!(err.log_level(), "{err}");
+  t(settings, err.message_key())
+}
@@ -0,0 +1,24 @@
+use crate::index;
+use std::time::Duration;
+
+const REAP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Deletes hosted files once they expire, forever. The proxy already refuses to serve
+/// them by then, this is what actually frees the space.
+pub async fn run() {
+  loop {
+    for entry in index::expired() {
+      match crate::backblaze::delete(&entry.bucket, &entry.key).await {
+        Ok(()) => {
+          info!("Reaped {}/{}", entry.bucket, entry.key);
+          index::bury(&entry.bucket, &entry.key);
+          crate::cache::evict(&format!("{}/{}", entry.bucket, entry.key)).await;
+        }
+        // try again next round
+        Err(err) => warn!("Could not reap {}/{}: {err:?}", entry.bucket, entry.key),
+      }
+    }
+
+    tokio::time::sleep(REAP_INTERVAL).await;
+  }
+}
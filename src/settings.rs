@@ -0,0 +1,174 @@
+use crate::error::SettingError;
+use crate::image;
+use crate::messages::{t, LANGUAGES, PERSONAS};
+use crate::store::Store;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup};
+
+const SETTINGS_FILE: &str = "settings.json";
+pub const CALLBACK_PREFIX: &str = "settings:";
+
+const SIZE_LIMITS_MB: &[u32] = &[10, 25, 50, 100, 250, 500];
+const CONTAINERS: &[&str] = &["mp4", "webm", "mkv"];
+const RESOLUTIONS: &[&str] = &["best", "1080", "720", "480", "360"];
+const AUDIO_CODECS: &[&str] = &["best", "mp3", "opus", "m4a"];
+const CAPTIONS: &[&str] = &["{title}", "{title}\n{url}", "{url}", ""];
+pub const IMAGE_REPLIES: &[&str] = &["links", "album"];
+
+lazy_static! {
+  static ref SETTINGS: Store<HashMap<i64, ChatSettings>> = Store::open(SETTINGS_FILE);
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ChatSettings {
+  /// Mirror supported links posted without a command.
+  pub auto_mirror: bool,
+  pub size_limit_mb: u32,
+  pub container: String,
+  /// Maximum video height, or `best`.
+  pub resolution: String,
+  /// Codec audio is converted to, or `best` to keep whatever the site serves.
+  pub audio_codec: String,
+  /// Caption for sent files. `{title}` and `{url}` are filled in.
+  pub caption: String,
+  /// What `/image` re-encodes pictures to, see `image::FORMATS`.
+  pub image_format: String,
+  /// Whether `/image` replies with links or an album.
+  pub image_reply: String,
+  pub language: String,
+  /// `pirate` or `plain`, see `messages`.
+  pub persona: String,
+  /// Delete our "downloading..." messages once the file is sent.
+  pub delete_status: bool,
+}
+
+impl Default for ChatSettings {
+  fn default() -> Self {
+    Self {
+      auto_mirror: false,
+      size_limit_mb: 50,
+      container: CONTAINERS[0].to_owned(),
+      resolution: RESOLUTIONS[0].to_owned(),
+      audio_codec: AUDIO_CODECS[0].to_owned(),
+      caption: CAPTIONS[0].to_owned(),
+      image_format: image::FORMATS[0].to_owned(),
+      image_reply: IMAGE_REPLIES[0].to_owned(),
+      language: LANGUAGES[0].to_owned(),
+      persona: PERSONAS[0].to_owned(),
+      delete_status: true,
+    }
+  }
+}
+
+impl ChatSettings {
+  pub fn caption(&self, title: &str, url: &str) -> String {
+    self.caption.replace("{title}", title).replace("{url}", url)
+  }
+
+  /// Advances `key` to its next preset value.
+  pub fn cycle(&mut self, key: &str) -> Result<(), SettingError> {
+    match key {
+      "size_limit" => self.size_limit_mb = next(SIZE_LIMITS_MB, &self.size_limit_mb),
+      "container" => self.container = next(CONTAINERS, &self.container.as_str()).to_owned(),
+      "resolution" => self.resolution = next(RESOLUTIONS, &self.resolution.as_str()).to_owned(),
+      "audio_codec" => self.audio_codec = next(AUDIO_CODECS, &self.audio_codec.as_str()).to_owned(),
+      "caption" => self.caption = next(CAPTIONS, &self.caption.as_str()).to_owned(),
+      "image_format" => {
+        self.image_format = next(image::FORMATS, &self.image_format.as_str()).to_owned()
+      }
+      "image_reply" => {
+        self.image_reply = next(IMAGE_REPLIES, &self.image_reply.as_str()).to_owned()
+      }
+      "language" => self.language = next(LANGUAGES, &self.language.as_str()).to_owned(),
+      "persona" => self.persona = next(PERSONAS, &self.persona.as_str()).to_owned(),
+      "delete_status" => self.delete_status = !self.delete_status,
+      _ => return Err(SettingError::UnknownKey(key.to_owned())),
+    }
+    Ok(())
+  }
+
+  /// Sets `key` to an arbitrary `value`, for things the presets don't cover.
+  pub fn set(&mut self, key: &str, value: &str) -> Result<(), SettingError> {
+    match key {
+      "size_limit" => self.size_limit_mb = parse(value)?,
+      "container" => self.container = one_of(CONTAINERS, value)?,
+      "resolution" => self.resolution = one_of(RESOLUTIONS, value)?,
+      "audio_codec" => self.audio_codec = one_of(AUDIO_CODECS, value)?,
+      "caption" => self.caption = value.replace("\\n", "\n"),
+      "image_format" => self.image_format = one_of(image::FORMATS, value)?,
+      "image_reply" => self.image_reply = one_of(IMAGE_REPLIES, value)?,
+      "language" => self.language = one_of(LANGUAGES, value)?,
+      "persona" => self.persona = one_of(PERSONAS, value)?,
+      "delete_status" => self.delete_status = parse(value)?,
+      _ => return Err(SettingError::UnknownKey(key.to_owned())),
+    }
+    Ok(())
+  }
+
+  /// One button per setting. Pressing a button cycles that setting.
+  pub fn keyboard(&self) -> InlineKeyboardMarkup {
+    let caption = match self.caption.as_str() {
+      "" => t(self, "setting_none").into(),
+      caption => caption.replace('\n', " ⏎ "),
+    };
+    let rows = [
+      ("size_limit", self.size_limit_mb.to_string()),
+      ("container", self.container.clone()),
+      ("resolution", self.resolution.clone()),
+      ("audio_codec", self.audio_codec.clone()),
+      ("caption", caption),
+      ("image_format", self.image_format.clone()),
+      ("image_reply", self.image_reply.clone()),
+      ("language", self.language.clone()),
+      ("persona", self.persona.clone()),
+      ("delete_status", self.delete_status.to_string()),
+    ];
+
+    let done =
+      InlineKeyboardButton::callback(t(self, "settings_done"), format!("{CALLBACK_PREFIX}done"));
+    InlineKeyboardMarkup::new(
+      rows
+        .into_iter()
+        .map(|(key, value)| {
+          let label = t(self, &format!("setting_{key}")).arg("value", value);
+          vec![InlineKeyboardButton::callback(
+            label,
+            format!("{CALLBACK_PREFIX}{key}"),
+          )]
+        })
+        .chain([vec![done]]),
+    )
+  }
+}
+
+fn next<T: PartialEq + Copy>(options: &[T], current: &T) -> T {
+  let i = options.iter().position(|o| o == current).unwrap_or(0);
+  options[(i + 1) % options.len()]
+}
+
+fn one_of(options: &[&str], value: &str) -> Result<String, SettingError> {
+  if !options.contains(&value) {
+    return Err(SettingError::NotAnOption {
+      value: value.to_owned(),
+      options: options.join(", "),
+    });
+  }
+  Ok(value.to_owned())
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, SettingError> {
+  value
+    .parse()
+    .map_err(|_| SettingError::BadValue(value.to_owned()))
+}
+
+pub fn get(chat: ChatId) -> ChatSettings {
+  SETTINGS.read().get(&chat.0).cloned().unwrap_or_default()
+}
+
+pub fn update<R>(chat: ChatId, f: impl FnOnce(&mut ChatSettings) -> R) -> R {
+  SETTINGS.update(|settings| f(settings.entry(chat.0).or_default()))
+}
@@ -1,104 +1,803 @@
-use anyhow::{bail, Context, Result};
+use crate::auth::{self, Access, Quota, Target};
+use crate::config::{self, ProxyRoute, Route};
+use crate::cookies;
+use crate::download::{self, Downloader, Fallback, File, Request};
+use crate::error::DownloadError;
+use crate::ids;
+use crate::image;
+use crate::index;
+use crate::messages::{self, t};
+use crate::settings::{self, ChatSettings};
+use crate::stats;
+use crate::store;
+use anyhow::{bail, Result};
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use glob::glob;
 use lazy_static::lazy_static;
+use mime_guess::mime;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use std::path::PathBuf;
-use teloxide::{prelude::*, types::InputFile, utils::command::BotCommands};
-use tokio::runtime::Runtime;
-use youtube_dl::{YoutubeDl, YoutubeDlOutput};
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+};
+use teloxide::{
+  dispatching::DpHandlerDescription,
+  net::Download,
+  prelude::*,
+  types::{
+    Chat, FileMeta, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia,
+    InputMediaPhoto,
+  },
+  utils::command::BotCommands,
+};
+use tokio::{io::AsyncWriteExt, runtime::Runtime};
 
-const TMP_DIR: &str = "video";
+// the most telegram lets bots upload, anything bigger gets hosted
 const DEFAULT_SIZE_LIMIT_MB: u32 = 50;
 const DEFAULT_SIZE_LIMIT: u64 = DEFAULT_SIZE_LIMIT_MB as u64 * 1_000_000;
+// bigger pictures have to go as documents
+const PHOTO_SIZE_LIMIT: u64 = 10_000_000;
+const MEDIA_GROUP_MAX: usize = 10;
+// bots can't fetch anything bigger through the file api
+const TELEGRAM_DOWNLOAD_LIMIT: u32 = 20_000_000;
+
+/// Where downloads wait before they're sent, under the state directory.
+fn tmp_dir() -> PathBuf {
+  store::state_dir().join("video")
+}
 
 lazy_static! {
   static ref DELAYED_CMD: (Sender<u64>, Receiver<u64>) = unbounded();
 }
 
 pub async fn start() {
-  let _ = std::fs::remove_dir_all(TMP_DIR);
-  let _ = std::fs::create_dir(TMP_DIR);
+  let _ = std::fs::remove_dir_all(tmp_dir());
+  let _ = std::fs::create_dir(tmp_dir());
 
   let bot = Bot::new(env!("TELEGRAM_BOT_KEY"));
   // let bot = Bot::new("6326192895:AAHqizQIGCJYoM5gOfqubOYaxwFkOoEhkOE");
 
-  Command::repl(bot, answer).await;
+  let handler = Update::filter_message()
+    .branch(dptree::entry().filter_command::<Command>().endpoint(answer))
+    .branch(dptree::filter(wants_hosting).endpoint(host_upload))
+    .branch(dptree::endpoint(auto_mirror));
+  let handler = dptree::entry().branch(handler).branch(
+    Update::filter_callback_query()
+      .branch(callback_with_prefix(settings::CALLBACK_PREFIX).endpoint(settings_callback))
+      .branch(callback_with_prefix(MINE_CALLBACK_PREFIX).endpoint(mine_callback)),
+  );
+
+  Dispatcher::builder(bot, handler)
+    .enable_ctrlc_handler()
+    .build()
+    .dispatch()
+    .await;
 }
 
 async fn answer(bot: Bot, msg: Message, cmd: Command) -> ResponseResult<()> {
-  match cmd {
-    Command::Help | Command::Start => {
+  let prefs = settings::get(msg.chat.id);
+
+  if cmd.admin_only() && !auth::is_admin(sender(&msg)) {
+    bot
+      .send_message(msg.chat.id, t(&prefs, "admin_only"))
+      .await?;
+    return Ok(());
+  }
+
+  if matches!(
+    cmd,
+    Command::Video(_) | Command::Audio(_) | Command::Song(_) | Command::Image(_) | Command::Host(_)
+  ) {
+    let access = auth::check(&msg);
+    if !matches!(access, Access::Granted) {
       bot
-        .send_message(msg.chat.id, Command::descriptions().to_string())
+        .send_message(msg.chat.id, access.refusal(&prefs))
         .await?;
       return Ok(());
     }
+  }
+
+  let reply: String = match cmd {
+    Command::Help | Command::Start => Command::descriptions().to_string(),
     Command::Video(args) => {
-      if let Ok(mut interaction) = Interaction::new(bot.clone(), msg, args).await {
-        if let Err(err) = interaction.download_video().await {
-          let _ = interaction.edit_response(format!("Oh my.. {err:?}")).await;
-        } else {
-          let _ = interaction.delete_response().await;
-        }
-        if let Ok(path) = interaction.file() {
-          let _ = std::fs::remove_file(path);
-        }
-      }
+      mirror(bot, msg, args, MirrorKind::Video).await;
+      return Ok(());
     }
     Command::Audio(args) => {
-      if let Ok(mut interaction) = Interaction::new(bot.clone(), msg, args).await {
-        let _ = interaction.download_audio().await;
-        let _ = interaction.delete_response().await;
-        if let Ok(path) = interaction.file() {
-          let _ = std::fs::remove_file(path);
+      mirror(bot, msg, args, MirrorKind::Audio).await;
+      return Ok(());
+    }
+    Command::Song(args) => {
+      mirror(bot, msg, args, MirrorKind::Song).await;
+      return Ok(());
+    }
+    Command::Image(args) => {
+      mirror(bot, msg, args, MirrorKind::Image).await;
+      return Ok(());
+    }
+    Command::Host(args) => {
+      mirror(bot, msg, args, MirrorKind::Host).await;
+      return Ok(());
+    }
+    Command::Allow(args) => match Target::parse(&args, &msg) {
+      Some(target) => {
+        auth::allow(target);
+        t(&prefs, "allowed").into()
+      }
+      None => t(&prefs, "acl_usage").into(),
+    },
+    Command::Deny(args) => match Target::parse(&args, &msg) {
+      Some(target) => {
+        auth::deny(target);
+        t(&prefs, "denied_target").into()
+      }
+      None => t(&prefs, "acl_usage").into(),
+    },
+    Command::Quota(args) => quota(&prefs, &msg, &args),
+    Command::Stats => stats::summary(&prefs, &tmp_dir()),
+    Command::Purge(args) => match purge(args.trim()).await {
+      Ok(path) => t(&prefs, "purged").arg("path", path).into(),
+      Err(err) => t(&prefs, "failed").arg("err", err).into(),
+    },
+    Command::Cache(args) => match args.trim() {
+      "clear" => t(&prefs, "cache_cleared")
+        .arg("count", crate::cache::clear().await)
+        .into(),
+      _ => t(&prefs, "cache_usage").into(),
+    },
+    Command::Cert => match tokio::task::spawn_blocking(crate::cert::days_left).await {
+      Ok(Ok(Some(days))) => t(&prefs, "cert_days").arg("days", days).into(),
+      Ok(Ok(None)) => t(&prefs, "cert_none").into(),
+      Ok(Err(err)) => t(&prefs, "failed").arg("err", err).into(),
+      Err(err) => t(&prefs, "failed").arg("err", err).into(),
+    },
+    Command::Automirror(args) => match args.trim() {
+      "on" | "off" if !can_configure(&bot, &msg).await? => t(&prefs, "officers_only").into(),
+      "on" => {
+        settings::update(msg.chat.id, |s| s.auto_mirror = true);
+        t(&prefs, "automirror_on").into()
+      }
+      "off" => {
+        settings::update(msg.chat.id, |s| s.auto_mirror = false);
+        t(&prefs, "automirror_off").into()
+      }
+      _ => {
+        let state = if prefs.auto_mirror { "on" } else { "off" };
+        t(&prefs, "automirror_status").arg("state", state).into()
+      }
+    },
+    Command::Settings(args) => {
+      let args = args.trim();
+      if args.is_empty() {
+        bot
+          .send_message(msg.chat.id, t(&prefs, "settings_menu"))
+          .reply_markup(prefs.keyboard())
+          .await?;
+        return Ok(());
+      }
+
+      if !can_configure(&bot, &msg).await? {
+        t(&prefs, "officers_only").into()
+      } else {
+        let (key, value) = args.split_once(' ').unwrap_or((args, ""));
+        match settings::update(msg.chat.id, |s| s.set(key, value.trim())) {
+          // reply in the new language if that's what changed
+          Ok(()) => t(&settings::get(msg.chat.id), "settings_updated")
+            .arg("key", key)
+            .into(),
+          Err(err) => {
+            warn!("Couldn't set {key} in {}: {err}", msg.chat.id);
+            err.reply(&prefs).into()
+          }
         }
       }
     }
-    Command::Song(args) => {
-      if let Ok(mut interaction) = Interaction::new(bot.clone(), msg, args).await {
-        let _ = interaction.download_song().await;
+    Command::Mine => {
+      let Some(user) = sender(&msg) else {
+        return Ok(());
+      };
+      let (text, keyboard) = mine_menu(&prefs, user);
+      bot
+        .send_message(msg.chat.id, text)
+        .reply_markup(keyboard)
+        .disable_web_page_preview(true)
+        .await?;
+      return Ok(());
+    }
+    Command::Cookies(args) => match cookies_cmd(&bot, &msg, &args).await {
+      Ok(reply) => reply,
+      Err(err) => {
+        warn!("/cookies {args} failed in {}: {err:?}", msg.chat.id);
+        t(&prefs, "cookies_failed").into()
+      }
+    },
+    Command::Ytdlp(args) => match args.trim() {
+      "update" => match tokio::task::spawn_blocking(update_ytdlp).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(err)) => t(&prefs, "failed").arg("err", err).into(),
+        Err(err) => t(&prefs, "failed").arg("err", err).into(),
+      },
+      _ => t(&prefs, "ytdlp_usage").into(),
+    },
+  };
+
+  bot.send_message(msg.chat.id, reply).await?;
+  Ok(())
+}
+
+/// Runs one download job start to finish, reporting failures in the status message.
+async fn mirror(bot: Bot, msg: Message, args: String, kind: MirrorKind) {
+  run(Interaction::new(bot, msg, args), kind).await;
+}
+
+async fn run(mut interaction: Interaction, kind: MirrorKind) {
+  // only count jobs that have something to work on, not ones missing their link
+  if interaction.has_input(kind) {
+    auth::record_job(sender(&interaction.msg));
+    stats::incr(&stats::JOBS, 1);
+  }
+
+  let result = match kind {
+    MirrorKind::Video => interaction.download_video().await,
+    MirrorKind::Audio => interaction.download_audio().await,
+    MirrorKind::Song => interaction.download_song().await,
+    MirrorKind::Image => interaction.download_images().await,
+    MirrorKind::Host => interaction.host_attachment().await,
+  };
+
+  match result {
+    Ok(()) => {
+      let _ = interaction.delete_response().await;
+    }
+    Err(err) => interaction.fail(err).await,
+  }
+
+  interaction.clean_up();
+}
+
+#[derive(Clone, Copy)]
+enum MirrorKind {
+  Video,
+  Audio,
+  Song,
+  Image,
+  /// A file sent through telegram rather than a link.
+  Host,
+}
+
+/// Links that get mirrored without a command when auto-mirror is on.
+/// A rule matches the domain and its subdomains, and optionally only paths
+/// starting with the given prefix. Image posts on gallery sites are picked up too.
+/// Plenty of posts on these have nothing to download, so their failures go unreported.
+const AUTO_MIRROR_RULES: &[(&str, Option<&str>, MirrorKind)] = &[
+  ("tiktok.com", None, MirrorKind::Video),
+  ("instagram.com", Some("/reel"), MirrorKind::Video),
+  ("youtube.com", Some("/shorts/"), MirrorKind::Video),
+  ("twitter.com", None, MirrorKind::Video),
+  ("x.com", None, MirrorKind::Video),
+  ("reddit.com", None, MirrorKind::Video),
+  ("soundcloud.com", None, MirrorKind::Audio),
+];
+
+fn auto_mirror_kind(url: &reqwest::Url) -> Option<MirrorKind> {
+  let host = url.host_str()?;
+  AUTO_MIRROR_RULES
+    .iter()
+    .find(|(domain, path, _)| {
+      (host == *domain || host.ends_with(&format!(".{domain}")))
+        && path.is_none_or(|path| url.path().starts_with(path))
+    })
+    .map(|(_, _, kind)| *kind)
+    .or_else(|| download::is_gallery(url).then_some(MirrorKind::Image))
+}
+
+/// Handles plain messages: mirrors supported links when the chat has auto-mirror on.
+async fn auto_mirror(bot: Bot, msg: Message) -> ResponseResult<()> {
+  let Some(text) = msg.text() else {
+    return Ok(());
+  };
+  if !settings::get(msg.chat.id).auto_mirror {
+    return Ok(());
+  }
+
+  let links: Vec<_> = text
+    .split_whitespace()
+    .filter_map(|word| reqwest::Url::parse(word).ok())
+    .filter_map(|url| Some((auto_mirror_kind(&url)?, url)))
+    .collect();
+
+  for (kind, url) in links {
+    // stay quiet here, nobody asked us for anything
+    if !matches!(auth::check(&msg), Access::Granted) {
+      break;
+    }
+
+    // links in text posts and the like fail, and nobody needs to hear about it
+    let mut interaction = Interaction::new(bot.clone(), msg.clone(), url.into());
+    interaction.quiet = true;
+    run(interaction, kind).await;
+  }
+
+  Ok(())
+}
+
+/// Media sent to the bot directly is hosted, as are files captioned `/host` anywhere.
+/// Documents sent directly aren't, they could be something like a `cookies.txt` on its
+/// way to `/cookies set`. Commands in captions don't reach `answer`, telegram only
+/// counts them in text.
+fn wants_hosting(msg: Message) -> bool {
+  let captioned = msg
+    .caption()
+    .is_some_and(|caption| caption.trim_start().starts_with("/host"));
+  let sent_directly = msg.chat.is_private() && msg.document().is_none();
+  Attachment::of(&msg).is_some() && (sent_directly || captioned)
+}
+
+async fn host_upload(bot: Bot, msg: Message) -> ResponseResult<()> {
+  let access = auth::check(&msg);
+  if !matches!(access, Access::Granted) {
+    let prefs = settings::get(msg.chat.id);
+    bot
+      .send_message(msg.chat.id, access.refusal(&prefs))
+      .await?;
+    return Ok(());
+  }
+
+  // `/host ttl=7d` in a caption works like the command
+  let args = msg
+    .caption()
+    .and_then(|caption| caption.trim_start().strip_prefix("/host"))
+    .unwrap_or_default()
+    .trim()
+    .to_owned();
+  mirror(bot, msg, args, MirrorKind::Host).await;
+  Ok(())
+}
+
+/// The file in a message, whatever kind of message it is.
+struct Attachment {
+  file: FileMeta,
+  name: Option<String>,
+  mime: Option<mime::Mime>,
+}
+
+impl Attachment {
+  fn of(msg: &Message) -> Option<Self> {
+    let (file, name, mime) = if let Some(doc) = msg.document() {
+      (&doc.file, &doc.file_name, &doc.mime_type)
+    } else if let Some(video) = msg.video() {
+      (&video.file, &video.file_name, &video.mime_type)
+    } else if let Some(animation) = msg.animation() {
+      (&animation.file, &animation.file_name, &animation.mime_type)
+    } else if let Some(audio) = msg.audio() {
+      (&audio.file, &audio.file_name, &audio.mime_type)
+    } else if let Some(voice) = msg.voice() {
+      (&voice.file, &None, &voice.mime_type)
+    } else {
+      // telegram sends a few sizes of every photo, the last is the largest
+      let photo = msg.photo()?.last()?;
+      return Some(Self {
+        file: photo.file.clone(),
+        name: None,
+        mime: Some(mime::IMAGE_JPEG),
+      });
+    };
+
+    Some(Self {
+      file: file.clone(),
+      name: name.clone(),
+      mime: mime.clone(),
+    })
+  }
+
+  fn extension(&self) -> String {
+    let from_name = self
+      .name
+      .as_deref()
+      .and_then(|name| Path::new(name).extension())
+      .map(|ext| ext.to_string_lossy().to_lowercase())
+      // the name is whatever the sender says, keep it to something safe in a key
+      .filter(|ext| !ext.is_empty() && ext.chars().all(|c| c.is_ascii_alphanumeric()));
+    let from_mime = || {
+      let mime = self.mime.as_ref()?;
+      mime_guess::get_mime_extensions(mime)?
+        .first()
+        .map(|ext| ext.to_string())
+    };
+
+    from_name
+      .or_else(from_mime)
+      .unwrap_or_else(|| "bin".to_owned())
+  }
+}
+
+/// Whether the sender may change this chat's settings.
+async fn can_configure(bot: &Bot, msg: &Message) -> ResponseResult<bool> {
+  let Some(user) = sender(msg) else {
+    return Ok(false);
+  };
+  is_chat_officer(bot, &msg.chat, user).await
+}
+
+async fn is_chat_officer(bot: &Bot, chat: &Chat, user: UserId) -> ResponseResult<bool> {
+  if chat.is_private() || auth::is_admin(Some(user)) {
+    return Ok(true);
+  }
+
+  Ok(bot.get_chat_member(chat.id, user).await?.is_privileged())
+}
+
+fn callback_with_prefix(
+  prefix: &'static str,
+) -> Handler<'static, DependencyMap, ResponseResult<()>, DpHandlerDescription> {
+  dptree::filter(move |query: CallbackQuery| {
+    query.data.as_deref().is_some_and(|d| d.starts_with(prefix))
+  })
+}
+
+/// Handles presses on the `/settings` keyboard.
+async fn settings_callback(bot: Bot, query: CallbackQuery) -> ResponseResult<()> {
+  let (Some(key), Some(menu)) = (
+    query
+      .data
+      .as_deref()
+      .and_then(|d| d.strip_prefix(settings::CALLBACK_PREFIX)),
+    &query.message,
+  ) else {
+    return Ok(());
+  };
+
+  if !is_chat_officer(&bot, &menu.chat, query.from.id).await? {
+    bot
+      .answer_callback_query(&query.id)
+      .text(t(&settings::get(menu.chat.id), "officers_only"))
+      .await?;
+    return Ok(());
+  }
+
+  if key == "done" {
+    bot.answer_callback_query(&query.id).await?;
+    bot.delete_message(menu.chat.id, menu.id).await?;
+    return Ok(());
+  }
+
+  let keyboard = settings::update(menu.chat.id, |s| s.cycle(key).map(|_| s.keyboard()));
+  match keyboard {
+    Ok(keyboard) => {
+      bot.answer_callback_query(&query.id).await?;
+      bot
+        .edit_message_reply_markup(menu.chat.id, menu.id)
+        .reply_markup(keyboard)
+        .await?;
+    }
+    Err(err) => {
+      warn!("Couldn't cycle {key} in {}: {err}", menu.chat.id);
+      bot
+        .answer_callback_query(&query.id)
+        .text(err.reply(&settings::get(menu.chat.id)))
+        .await?;
+    }
+  }
+
+  Ok(())
+}
+
+fn sender(msg: &Message) -> Option<UserId> {
+  msg.from().map(|u| u.id)
+}
+
+/// `/quota` shows the sender's usage, `/quota <user id|default> <jobs> <MB>` sets a quota.
+fn quota(prefs: &ChatSettings, msg: &Message, args: &str) -> String {
+  let args: Vec<&str> = args.split_whitespace().collect();
+
+  if args.is_empty() {
+    let Some(user) = sender(msg) else {
+      return t(prefs, "quota_who").into();
+    };
+    let (quota, usage) = auth::usage(user);
+    return t(prefs, "quota_self")
+      .arg("jobs", usage.jobs)
+      .arg("max_jobs", quota.jobs)
+      .arg("mb", usage.bytes / 1_000_000)
+      .arg("max_mb", quota.bytes / 1_000_000)
+      .into();
+  }
+
+  if !auth::is_admin(sender(msg)) {
+    return t(prefs, "quota_admin_only").into();
+  }
+
+  let [who, jobs, mb] = args[..] else {
+    return t(prefs, "quota_usage").into();
+  };
+  let (Ok(jobs), Ok(mb)) = (jobs.parse::<u32>(), mb.parse::<u64>()) else {
+    return t(prefs, "quota_numbers").into();
+  };
+  let user = match who {
+    "default" => None,
+    id => match id.parse() {
+      Ok(id) => Some(id),
+      Err(_) => return t(prefs, "quota_bad_user").arg("id", id).into(),
+    },
+  };
+
+  auth::set_quota(
+    user,
+    Quota {
+      jobs,
+      bytes: mb * 1_000_000,
+    },
+  );
+  t(prefs, "quota_set")
+    .arg("who", who)
+    .arg("jobs", jobs)
+    .arg("mb", mb)
+    .into()
+}
+
+/// Deletes a hosted object. Accepts a bare id (`abcde.png`), a path (`v/abcde.mp4`)
+/// or a full link.
+async fn purge(id: &str) -> Result<String> {
+  let url = id
+    .trim_start_matches("https://")
+    .trim_start_matches("http://");
+  let found = match url.split_once('/') {
+    Some((host, path)) if url.len() < id.len() => {
+      crate::http::locate(Some(host), &format!("/{path}"))
+    }
+    _ if url.is_empty() => bail!("Usage: /purge <id>"),
+    _ => crate::http::resolve(url),
+  };
+  let Some((route, key)) = found else {
+    bail!("Nothing is hosted at {id}");
+  };
+
+  crate::backblaze::delete(&route.bucket, &key).await?;
+  crate::cache::evict(&format!("{}/{key}", route.bucket)).await;
+  index::bury(&route.bucket, &key);
+
+  Ok(link(&route.bucket, &key))
+}
+
+/// `/mine` shows this many of a user's uploads, newest first.
+const MINE_LIMIT: usize = 10;
+const MINE_CALLBACK_PREFIX: &str = "mine:";
+
+/// A user's recent uploads, with a delete button for each.
+fn mine_menu(prefs: &ChatSettings, user: UserId) -> (String, InlineKeyboardMarkup) {
+  let entries = index::list(|entry| entry.uploaded_by(user.0));
+  if entries.is_empty() {
+    return (
+      t(prefs, "mine_empty").into(),
+      InlineKeyboardMarkup::default(),
+    );
+  }
+
+  let now = index::now();
+  let lines: Vec<String> = entries
+    .iter()
+    .take(MINE_LIMIT)
+    .enumerate()
+    .map(|(i, entry)| {
+      let title = entry.title.as_deref().unwrap_or_default();
+      format!(
+        "{}. {} {title} ({}MB, {}d ago)",
+        i + 1,
+        link(&entry.bucket, &entry.key),
+        entry.size / 1_000_000,
+        now.saturating_sub(entry.created) / 86_400
+      )
+    })
+    .collect();
+
+  let buttons = entries
+    .iter()
+    .take(MINE_LIMIT)
+    .enumerate()
+    .map(|(i, entry)| {
+      vec![InlineKeyboardButton::callback(
+        format!("🗑 {}. {}", i + 1, entry.key),
+        format!(
+          "{MINE_CALLBACK_PREFIX}{}",
+          index::handle(&entry.bucket, &entry.key)
+        ),
+      )]
+    });
+
+  let text = format!(
+    "{}\n\n{}",
+    t(prefs, "mine_header").arg("count", entries.len()),
+    lines.join("\n")
+  );
+  (text, InlineKeyboardMarkup::new(buttons))
+}
+
+/// Handles presses on the `/mine` delete buttons.
+async fn mine_callback(bot: Bot, query: CallbackQuery) -> ResponseResult<()> {
+  let Some(menu) = &query.message else {
+    return Ok(());
+  };
+  let prefs = settings::get(menu.chat.id);
+  let entry = query
+    .data
+    .as_deref()
+    .and_then(|d| d.strip_prefix(MINE_CALLBACK_PREFIX))
+    .and_then(index::by_handle);
+  // gone already, most likely pressed twice
+  let Some(entry) = entry else {
+    bot.answer_callback_query(&query.id).await?;
+    return Ok(());
+  };
+  let (bucket, key) = (entry.bucket.as_str(), entry.key.as_str());
+
+  let presser = query.from.id;
+  let admin = auth::is_admin(Some(presser));
+  if !entry.uploaded_by(presser.0) && !admin {
+    bot
+      .answer_callback_query(&query.id)
+      .text(t(&prefs, "mine_not_yours"))
+      .await?;
+    return Ok(());
+  }
+
+  let mut others = entry.clone();
+  let shared = others.remove_uploader(presser.0);
+  let reply = if shared && !admin {
+    // others uploaded it too, so it only leaves this list
+    index::update(bucket, key, |entry| {
+      entry.remove_uploader(presser.0);
+    });
+    t(&prefs, "mine_unlisted").arg("path", key)
+  } else {
+    match crate::backblaze::delete(bucket, key).await {
+      Ok(()) => {
+        index::bury(bucket, key);
+        crate::cache::evict(&format!("{bucket}/{key}")).await;
+        t(&prefs, "purged").arg("path", key)
       }
+      Err(err) => t(&prefs, "failed").arg("err", err),
     }
   };
+  bot.answer_callback_query(&query.id).text(reply).await?;
+
+  // the menu belongs to whoever asked for it, even if an admin pressed the button
+  let user = match entry.uploaded_by(presser.0) {
+    true => presser,
+    false => entry.uploader.map(UserId).unwrap_or(presser),
+  };
+  let (text, keyboard) = mine_menu(&prefs, user);
+  bot
+    .edit_message_text(menu.chat.id, menu.id, text)
+    .reply_markup(keyboard)
+    .disable_web_page_preview(true)
+    .await?;
 
   Ok(())
 }
 
+/// `/cookies` lists the jars, `/cookies set <domain>` in reply to a cookie file stores it,
+/// `/cookies rm <domain>` deletes one.
+async fn cookies_cmd(bot: &Bot, msg: &Message, args: &str) -> Result<String> {
+  let prefs = settings::get(msg.chat.id);
+  let args: Vec<&str> = args.split_whitespace().collect();
+
+  match args[..] {
+    [] => Ok(cookies::summary()),
+    ["set", domain] => {
+      let Ok(domain) = cookies::normalize_domain(domain) else {
+        return Ok(t(&prefs, "cookies_bad_domain").arg("domain", domain).into());
+      };
+      let upload = msg.reply_to_message().filter(|m| m.document().is_some());
+      let Some(document) = upload.and_then(|m| m.document()) else {
+        return Ok(t(&prefs, "cookies_no_file").into());
+      };
+
+      let file = bot.get_file(&document.file.id).await?;
+      let mut contents = Vec::new();
+      bot.download_file(&file.path, &mut contents).await?;
+
+      if let Err(err) = cookies::Jar::parse(&String::from_utf8_lossy(&contents)) {
+        warn!("Rejected the cookies for {domain}: {err}");
+        return Ok(t(&prefs, "cookies_bad_file").into());
+      }
+      let jar = cookies::save(&domain, &contents)?;
+      // no reason to leave logins lying around in the chat
+      if let Some(upload) = upload {
+        let _ = bot.delete_message(upload.chat.id, upload.id).await;
+      }
+
+      Ok(
+        t(&prefs, "cookies_saved")
+          .arg("domain", domain)
+          .arg("state", jar.describe())
+          .into(),
+      )
+    }
+    ["rm", domain] => {
+      let Ok(domain) = cookies::normalize_domain(domain) else {
+        return Ok(t(&prefs, "cookies_bad_domain").arg("domain", domain).into());
+      };
+      let key = match cookies::remove(&domain)? {
+        true => "cookies_removed",
+        false => "cookies_none",
+      };
+      Ok(t(&prefs, key).arg("domain", domain).into())
+    }
+    _ => Ok(t(&prefs, "cookies_usage").into()),
+  }
+}
+
+fn update_ytdlp() -> Result<String> {
+  let output = std::process::Command::new("yt-dlp").arg("-U").output()?;
+  let mut report = String::from_utf8_lossy(&output.stdout).into_owned();
+  report.push_str(&String::from_utf8_lossy(&output.stderr));
+
+  if !output.status.success() {
+    bail!("yt-dlp -U failed: {report}");
+  }
+  Ok(report)
+}
+
 struct Interaction {
   id: String,
   bot: Bot,
   msg: Message,
+  settings: ChatSettings,
   size_limit: u32,
   args: Vec<String>,
+  /// `key=value` arguments, like `via=tunnel`.
+  options: HashMap<String, String>,
   response: Option<Message>,
+  /// Whether failures and retries go unreported, for jobs nobody asked for.
+  quiet: bool,
 }
 
 impl Interaction {
-  async fn new(bot: Bot, msg: Message, args: String) -> Result<Self> {
-    let args: Vec<String> = args.split(" ").map(|a| a.to_owned()).collect();
+  fn new(bot: Bot, msg: Message, args: String) -> Self {
+    let (options, args): (Vec<&str>, Vec<&str>) = args.split(' ').partition(|a| is_option(a));
+    let options = options
+      .into_iter()
+      .filter_map(|o| o.split_once('='))
+      .map(|(k, v)| (k.to_owned(), v.to_owned()))
+      .collect();
+    let args: Vec<String> = args.into_iter().map(|a| a.to_owned()).collect();
+    let settings = settings::get(msg.chat.id);
 
-    let mut interaction = Self {
+    Self {
       id: rand_string(5),
       bot,
       msg,
-      size_limit: Self::size_limit(&args),
+      size_limit: Self::size_limit(&args, settings.size_limit_mb),
+      settings,
       args,
+      options,
       response: None,
-    };
+      quiet: false,
+    }
+  }
 
-    if interaction.args[0].trim().is_empty() {
-      interaction
-        .respond("Oh sir.. did you mean to give a url? I didn't get one.")
-        .await
-        .unwrap();
-      bail!("No URL");
+  fn has_input(&self, kind: MirrorKind) -> bool {
+    match kind {
+      MirrorKind::Host => {
+        Attachment::of(&self.msg).is_some()
+          || self
+            .msg
+            .reply_to_message()
+            .and_then(Attachment::of)
+            .is_some()
+      }
+      _ => self.url().is_ok(),
     }
+  }
 
-    Ok(interaction)
+  fn url(&self) -> Result<&str, DownloadError> {
+    match self.args.first().map(|url| url.trim()) {
+      None | Some("") => Err(DownloadError::MissingUrl),
+      Some(url) => Ok(url),
+    }
   }
 
-  async fn respond(&mut self, msg: impl AsRef<str>) -> Result<()> {
+  async fn respond(&mut self, msg: impl AsRef<str>) -> Result<(), DownloadError> {
     let msg = self
       .bot
       .send_message(self.msg.chat.id, msg.as_ref())
@@ -108,7 +807,7 @@ impl Interaction {
     Ok(())
   }
 
-  async fn edit_response(&mut self, msg: impl AsRef<str>) -> Result<()> {
+  async fn edit_response(&mut self, msg: impl AsRef<str>) -> Result<(), DownloadError> {
     if let Some(response) = &self.response {
       let response = self
         .bot
@@ -121,7 +820,10 @@ impl Interaction {
     Ok(())
   }
 
-  async fn delete_response(&mut self) -> Result<()> {
+  async fn delete_response(&mut self) -> Result<(), DownloadError> {
+    if !self.settings.delete_status {
+      return Ok(());
+    }
     if let Some(response) = &self.response {
       self
         .bot
@@ -132,179 +834,512 @@ impl Interaction {
     Ok(())
   }
 
-  fn size_limit(params: &[impl AsRef<str>]) -> u32 {
+  /// Replaces the status message with what went wrong, in the chat's words.
+  async fn fail(&mut self, err: DownloadError) {
+    if self.quiet {
+      info!("Quietly gave up on {:?}: {err}", self.args);
+      if let Some(response) = self.response.take() {
+        let _ = self.bot.delete_message(response.chat.id, response.id).await;
+      }
+      return;
+    }
+    let reply = messages::error(&self.settings, &err);
+    if let Err(err) = self.edit_response(reply).await {
+      warn!("Could not report a failure: {err}");
+    }
+  }
+
+  fn size_limit(params: &[impl AsRef<str>], default: u32) -> u32 {
     if let Some(size_limit) = params.get(1) {
-      return size_limit.as_ref().parse().unwrap_or(DEFAULT_SIZE_LIMIT_MB);
+      return size_limit.as_ref().parse().unwrap_or(default);
     }
-    DEFAULT_SIZE_LIMIT_MB
+    default
   }
 
-  async fn download_song(&mut self) -> Result<()> {
+  async fn download_song(&mut self) -> Result<(), DownloadError> {
+    self.url()?;
     self
-      .respond(format!(
-        r#"Aye-aye cap'n! Let me ask the crew if they've heard of a song by the name "{}""#,
-        self.args.join(" ")
-      ))
+      .respond(t(&self.settings, "song_start").arg("query", self.args.join(" ")))
       .await?;
 
     let bot = self.bot.clone();
     let chat_id = self.msg.chat.id;
-    let args = self.args.clone();
+    let user = sender(&self.msg);
+    let query = self.args.join(" ");
 
-    std::thread::spawn(move || -> Result<()> {
-      let rt = Runtime::new().unwrap();
+    // the spotify session isn't Send, so it gets a runtime of its own
+    let song = tokio::task::spawn_blocking(move || {
+      let rt = Runtime::new()?;
+      rt.block_on(crate::music::dl_search(query))
+    })
+    .await
+    .map_err(|err| DownloadError::Downloader(err.to_string()))?
+    .map_err(|err| DownloadError::Downloader(format!("{err:?}")))?;
 
-      rt.block_on(async {
-        let song = crate::music::dl_search(args.join(" ")).await.unwrap();
-        let input_file = InputFile::memory(song);
-        bot
-          .send_audio(chat_id, input_file)
-          .caption("song.ogg")
-          .await
-          .unwrap();
-      });
-
-      Ok(())
-    });
+    auth::record_bytes(user, song.len() as u64);
+    bot
+      .send_audio(chat_id, InputFile::memory(song))
+      .caption("song.ogg")
+      .await?;
 
     Ok(())
   }
 
-  async fn download_audio(&mut self) -> Result<()> {
+  async fn download_audio(&mut self) -> Result<(), DownloadError> {
+    let url = self.url()?.to_owned();
+    self.expires()?;
     self
-      .respond(format!(
-        "Oh sure, cap'n! I'll get that for you. ({}MB limit)",
-        self.size_limit
-      ))
+      .respond(t(&self.settings, "audio_start").arg("limit", self.size_limit))
       .await?;
 
-    let outfile = format!("{}/{}.%(ext)s", TMP_DIR, self.id);
-    let dl_cmd = dl_cmd(&self.args[0], self.size_limit, &outfile)
-      .extract_audio(true)
-      .to_owned();
+    let files = self
+      .run_download(download::for_url(&url), &url, true)
+      .await?;
+    self.deliver(MirrorKind::Audio, files).await
+  }
 
-    let (file_path, caption) = self.run_download(&dl_cmd).await?;
+  async fn download_video(&mut self) -> Result<(), DownloadError> {
+    let url = self.url()?.to_owned();
+    self.expires()?;
+    self
+      .respond(t(&self.settings, "video_start").arg("limit", self.size_limit))
+      .await?;
+
+    let files = self
+      .run_download(download::for_url(&url), &url, false)
+      .await?;
+    self.deliver(MirrorKind::Video, files).await
+  }
 
-    let file = std::fs::File::open(&file_path)?;
-    let filesize = file.metadata().unwrap().len();
+  /// Mirrors the pictures in a post or gallery to i-kota, re-encoded if the chat
+  /// wants that, and replies with their links or an album.
+  async fn download_images(&mut self) -> Result<(), DownloadError> {
+    let url = self.url()?.to_owned();
+    self.expires()?;
+    let format = self.option("format", image::FORMATS, &self.settings.image_format)?;
+    let reply = self.option("reply", settings::IMAGE_REPLIES, &self.settings.image_reply)?;
+    self.respond(t(&self.settings, "image_start")).await?;
 
-    if filesize >= DEFAULT_SIZE_LIMIT {
-      let host_msg =
-        "Oh Cap'n, this file is too large for Telegram. Let me host it for you!\n\nUploading...";
-      self.edit_response(host_msg).await?;
+    let files = self
+      .run_download(download::for_images(&url), &url, false)
+      .await?;
 
-      let extension = file_path.extension().unwrap().to_string_lossy();
+    self
+      .edit_response(t(&self.settings, "image_uploading"))
+      .await?;
+    let mut hosted = Vec::new();
+    for file in files {
+      let path = match format.as_str() {
+        "original" => file.path,
+        _ if !image::is_still(&file.path) => file.path,
+        format => image::reencode(&file.path, format)
+          .await
+          .unwrap_or_else(|err| {
+            warn!("Keeping the original: {err:?}");
+            file.path
+          }),
+      };
+      let filesize = std::fs::metadata(&path)?.len();
+      auth::record_bytes(sender(&self.msg), filesize);
 
-      let s3_path = format!("{}.{extension}", self.id);
-      crate::backblaze::put_vid(&s3_path, &file_path).await?;
+      let link = self.upload("i-kota", &path, file.title.as_deref()).await?;
+      hosted.push((
+        File {
+          path,
+          title: file.title,
+        },
+        filesize,
+        link,
+      ));
+    }
 
-      self
-        .edit_response(format!(
-          "Here it is, Cap'n! https://kota.is/v/{}.{extension}",
-          self.id
-        ))
-        .await?;
-      self.response = None;
+    let links: Vec<&str> = hosted.iter().map(|(_, _, link)| link.as_str()).collect();
+    let links = links.join("\n");
+    let photos: Vec<&File> = hosted
+      .iter()
+      .filter(|(file, filesize, _)| {
+        file.mime().type_() == mime::IMAGE && *filesize < PHOTO_SIZE_LIMIT
+      })
+      .map(|(file, _, _)| file)
+      .collect();
 
+    if reply == "album" && !photos.is_empty() {
+      self.edit_response(t(&self.settings, "sending")).await?;
+      // telegram caps captions at 1024 characters, links that don't fit are still in the bucket
+      let mut caption = links;
+      caption.truncate(1024);
+      self.send_photos(&photos, &mut Some(caption)).await?;
       return Ok(());
     }
 
-    self
-      .edit_response("I got the file, sir! Sending it now...")
-      .await?;
+    self.reply_hosted(&links).await
+  }
+
+  /// Uploads the file attached to the message, or to the one it replies to,
+  /// and replies with its link.
+  async fn host_attachment(&mut self) -> Result<(), DownloadError> {
+    let attachment = Attachment::of(&self.msg)
+      .or_else(|| self.msg.reply_to_message().and_then(Attachment::of))
+      .ok_or(DownloadError::MissingFile)?;
+    self.expires()?;
+
+    if attachment.file.size > TELEGRAM_DOWNLOAD_LIMIT {
+      return Err(DownloadError::TooLarge(format!(
+        "telegram won't let bots download {} bytes",
+        attachment.file.size
+      )));
+    }
+    self.respond(t(&self.settings, "host_start")).await?;
 
+    let path = tmp_dir().join(format!("{}.{}", self.id, attachment.extension()));
+    let file = self.bot.get_file(&attachment.file.id).await?;
+    let mut dst = tokio::fs::File::create(&path).await?;
     self
       .bot
-      .send_audio(self.msg.chat.id, InputFile::file(&file_path))
-      .caption(caption)
+      .download_file(&file.path, &mut dst)
+      .await
+      .map_err(|err| DownloadError::Downloader(err.to_string()))?;
+    dst.flush().await?;
+    auth::record_bytes(sender(&self.msg), attachment.file.size as u64);
+
+    let mime = attachment
+      .mime
+      .clone()
+      .unwrap_or_else(|| mime_guess::from_path(&path).first_or_octet_stream());
+    let bucket = match mime.type_() {
+      mime::IMAGE => "i-kota",
+      _ => "v-kota",
+    };
+    let url = self
+      .upload(bucket, &path, attachment.name.as_deref())
       .await?;
 
-    self.delete_response().await?;
+    self.reply_hosted(&url).await
+  }
 
+  /// Replaces the status message with links to hosted files, and keeps it around.
+  async fn reply_hosted(&mut self, links: &str) -> Result<(), DownloadError> {
+    let reply = match self.options.get("ttl") {
+      Some(ttl) => t(&self.settings, "hosted_ttl").arg("ttl", ttl),
+      None => t(&self.settings, "hosted"),
+    };
+    self.edit_response(reply.arg("url", links)).await?;
+    self.response = None;
     Ok(())
   }
 
-  async fn download_video(&mut self) -> Result<()> {
-    self
-      .respond(format!(
-        "Aye-aye cap'n! Downloading video with a {}MB filesize limit.",
-        self.size_limit
-      ))
-      .await?;
+  /// When files hosted for this job expire, from `ttl=<number><s|m|h|d|w>`.
+  fn expires(&self) -> Result<Option<u64>, DownloadError> {
+    let Some(ttl) = self.options.get("ttl") else {
+      return Ok(None);
+    };
+    let seconds = parse_ttl(ttl).ok_or_else(|| DownloadError::BadOption(format!("ttl={ttl}")))?;
+    Ok(Some(index::now() + seconds))
+  }
 
-    let outfile = format!("{}/{}.%(ext)s", TMP_DIR, self.id);
-    let dl_cmd = dl_cmd(&self.args[0], self.size_limit, &outfile)
-      .format("mp4")
-      .to_owned();
+  /// Uploads a file under a fresh id, records who it's from, and returns its link.
+  async fn upload(
+    &self,
+    bucket: &str,
+    path: &Path,
+    title: Option<&str>,
+  ) -> Result<String, DownloadError> {
+    let extension = path
+      .extension()
+      .map(|ext| ext.to_string_lossy().into_owned())
+      .unwrap_or_default();
+    let claimed = ids::claim(bucket, path, &extension, self.expires()?)
+      .await
+      .map_err(DownloadError::Storage)?;
 
-    let (file_path, caption) = self.run_download(&dl_cmd).await?;
+    let uploader = sender(&self.msg).map(|user| user.0);
+    let source = self.url().ok().map(str::to_owned);
+    if claimed.existing {
+      // the same contents again, which is theirs now too
+      index::update(bucket, &claimed.key, |entry| {
+        if let Some(uploader) = uploader {
+          entry.add_uploader(uploader);
+        }
+        entry.source = entry.source.take().or(source);
+        entry.title = entry.title.take().or(title.map(str::to_owned));
+      });
+    } else {
+      if let Err(err) = crate::backblaze::put(bucket, &claimed.key, path).await {
+        index::remove(bucket, &claimed.key);
+        return Err(DownloadError::Storage(err));
+      }
 
-    let file = std::fs::File::open(&file_path)?;
-    let filesize = file.metadata().unwrap().len();
+      let size = std::fs::metadata(path)?.len();
+      index::update(bucket, &claimed.key, |entry| {
+        entry.uploader = uploader;
+        entry.source = source;
+        entry.title = title.map(str::to_owned);
+        entry.size = size;
+      });
 
-    if filesize >= DEFAULT_SIZE_LIMIT {
-      let host_msg =
-        "Oh Cap'n, this file is too large for Telegram. Let me host it for you!\n\nUploading...";
-      self.edit_response(host_msg).await?;
+      let (bucket, key) = (bucket.to_owned(), claimed.key.clone());
+      tokio::spawn(async move {
+        if let Err(status) = crate::http::warm(&bucket, &key).await {
+          warn!("Couldn't warm the cache for {bucket}/{key}: {status}");
+        }
+      });
+    }
 
-      let s3_path = format!("{}.mp4", self.id);
-      crate::backblaze::put_vid(&s3_path, &file_path).await?;
+    Ok(link(bucket, &claimed.key))
+  }
 
-      self
-        .edit_response(format!(
-          "Here it is, Cap'n! https://kota.is/v/{}.mp4",
-          self.id
-        ))
-        .await?;
-      self.response = None;
+  /// A `key=value` option from the command, or the chat's setting for it.
+  fn option(&self, key: &str, allowed: &[&str], default: &str) -> Result<String, DownloadError> {
+    match self.options.get(key) {
+      Some(value) if allowed.contains(&value.as_str()) => Ok(value.clone()),
+      Some(value) => Err(DownloadError::BadOption(format!("{key}={value}"))),
+      None => Ok(default.to_owned()),
+    }
+  }
 
-      return Ok(());
+  /// Sends pictures as albums, or on their own when there's just one.
+  /// The caption goes on the first picture, if it's still there to take.
+  async fn send_photos(
+    &self,
+    photos: &[&File],
+    caption: &mut Option<String>,
+  ) -> Result<(), DownloadError> {
+    let chat_id = self.msg.chat.id;
+
+    for album in photos.chunks(MEDIA_GROUP_MAX) {
+      if let [photo] = album {
+        self
+          .bot
+          .send_photo(chat_id, InputFile::file(&photo.path))
+          .caption(caption.take().unwrap_or_default())
+          .await?;
+        continue;
+      }
+
+      let media = album.iter().map(|photo| {
+        let mut media = InputMediaPhoto::new(InputFile::file(&photo.path));
+        if let Some(caption) = caption.take() {
+          media = media.caption(caption);
+        }
+        InputMedia::Photo(media)
+      });
+      self.bot.send_media_group(chat_id, media).await?;
     }
 
-    self
-      .edit_response("I got the file, sir! Sending it now...")
-      .await?;
+    Ok(())
+  }
 
-    self
-      .bot
-      .send_video(self.msg.chat.id, InputFile::file(&file_path))
-      .supports_streaming(true)
-      .caption(caption)
-      .await?;
+  /// Sends what was downloaded: pictures as albums, everything else one by one.
+  /// Files too big for telegram get uploaded and linked instead.
+  async fn deliver(&mut self, kind: MirrorKind, files: Vec<File>) -> Result<(), DownloadError> {
+    let title = files
+      .iter()
+      .find_map(|file| file.title.clone())
+      .unwrap_or_else(|| t(&self.settings, "no_title").into());
+    let mut caption = self.settings.caption(&title, &self.args[0]);
+    // titles can be any text, so cut between characters rather than bytes
+    if let Some((end, _)) = caption.char_indices().nth(200) {
+      caption.truncate(end);
+    }
+
+    let mut photos = Vec::new();
+    let mut others = Vec::new();
+    let mut links = Vec::new();
+    for file in files {
+      let filesize = std::fs::metadata(&file.path)?.len();
+      auth::record_bytes(sender(&self.msg), filesize);
+
+      if filesize >= DEFAULT_SIZE_LIMIT {
+        if links.is_empty() {
+          self.edit_response(t(&self.settings, "too_large")).await?;
+        }
+        links.push(
+          self
+            .upload("v-kota", &file.path, file.title.as_deref())
+            .await?,
+        );
+      } else if file.mime().type_() == mime::IMAGE && filesize < PHOTO_SIZE_LIMIT {
+        photos.push(file);
+      } else {
+        others.push(file);
+      }
+    }
+
+    let sent_here = !photos.is_empty() || !others.is_empty();
+    if sent_here {
+      self.edit_response(t(&self.settings, "sending")).await?;
+    }
+
+    // only the first message gets a caption
+    let mut caption = Some(caption);
+    let chat_id = self.msg.chat.id;
+
+    let photos: Vec<&File> = photos.iter().collect();
+    self.send_photos(&photos, &mut caption).await?;
+
+    for file in others {
+      let input = InputFile::file(&file.path);
+      let caption = caption.take().unwrap_or_default();
+      match (kind, file.mime().type_()) {
+        (MirrorKind::Audio, _) | (_, mime::AUDIO) => {
+          self.bot.send_audio(chat_id, input).caption(caption).await?
+        }
+        (_, mime::VIDEO) => {
+          self
+            .bot
+            .send_video(chat_id, input)
+            .supports_streaming(true)
+            .caption(caption)
+            .await?
+        }
+        _ => {
+          self
+            .bot
+            .send_document(chat_id, input)
+            .caption(caption)
+            .await?
+        }
+      };
+    }
+
+    if !links.is_empty() {
+      self.reply_hosted(&links.join("\n")).await?;
+    }
+    // whatever went to telegram stays there, ttl= only reaches hosted links
+    if sent_here && self.options.contains_key("ttl") {
+      self
+        .bot
+        .send_message(chat_id, t(&self.settings, "ttl_not_hosted"))
+        .await?;
+    }
 
     Ok(())
   }
 
-  async fn run_download(&mut self, dl_cmd: &YoutubeDl) -> Result<(PathBuf, String)> {
-    let result = dl_cmd.run()?;
+  /// The network route for this download: whatever `via=` asks for, otherwise the
+  /// one configured for the url's domain. `via=direct` skips routing.
+  fn route(&self, url: &str) -> Result<Option<&'static Route>, DownloadError> {
+    let download = &config::get().download;
+    match self.options.get("via").map(String::as_str) {
+      Some("direct") => Ok(None),
+      Some(name) => download
+        .route(name)
+        .map(Some)
+        .ok_or_else(|| DownloadError::UnknownRoute(name.to_owned())),
+      None => Ok(download.route_for(url)),
+    }
+  }
 
-    let mut caption: String = match result {
-      YoutubeDlOutput::SingleVideo(video) => video.title,
-      _ => String::from("No Title Found"),
-    };
-    caption.truncate(200);
+  /// Downloads with whichever backend handles `url`, retrying transient failures
+  /// with backoff and working through the fallbacks until one works or we're out of attempts.
+  async fn run_download(
+    &mut self,
+    backend: &'static dyn Downloader,
+    url: &str,
+    audio_only: bool,
+  ) -> Result<Vec<File>, DownloadError> {
+    let policy = &config::get().download;
+    let route = self.route(url)?;
+    match route {
+      Some(route) => info!(
+        "Downloading {url} with {} via {}",
+        backend.name(),
+        route.name
+      ),
+      None => info!("Downloading {url} with {}", backend.name()),
+    }
+
+    let out = tmp_dir().join(&self.id);
+    let mut attempt = 1;
 
-    Ok((self.file()?, caption))
+    loop {
+      let fallback = Fallback::for_attempt(attempt);
+      info!(
+        "Download attempt {attempt}/{} for {url}: {fallback:?}",
+        policy.attempts
+      );
+
+      let request = Request {
+        url,
+        audio_only,
+        size_limit_mb: self.size_limit,
+        settings: &self.settings,
+        route,
+        fallback,
+        out: &out,
+      };
+      let err = match backend.download(&request).await {
+        Ok(files) => return Ok(files),
+        Err(err) => err,
+      };
+      if attempt >= policy.attempts || !err.is_retryable() {
+        return Err(err);
+      }
+
+      let next = Fallback::for_attempt(attempt + 1);
+      warn!("Attempt {attempt} for {url} failed, retrying {next:?}: {err}");
+      self.clean_up();
+      if !self.quiet {
+        self
+          .edit_response(
+            t(&self.settings, "retrying")
+              .arg("attempt", attempt + 1)
+              .arg("attempts", policy.attempts)
+              .arg("how", t(&self.settings, next.message_key())),
+          )
+          .await?;
+      }
+
+      tokio::time::sleep(policy.backoff(attempt)).await;
+      attempt += 1;
+    }
   }
 
-  fn file(&self) -> Result<PathBuf> {
-    glob(&format!("{}/{}*", TMP_DIR, self.id))?
-      .nth(0)
-      .map(|pb| pb.unwrap())
-      .context("No download found.")
+  /// Removes whatever was downloaded for this interaction, partial files included.
+  fn clean_up(&self) {
+    let Ok(paths) = glob(&format!("{}/{}*", tmp_dir().display(), self.id)) else {
+      return;
+    };
+    for path in paths.flatten() {
+      let _ = match path.is_dir() {
+        true => std::fs::remove_dir_all(path),
+        false => std::fs::remove_file(path),
+      };
+    }
   }
 }
 
-fn dl_cmd(url: &str, size_limit: u32, outfile: &str) -> YoutubeDl {
-  let dl_cmd = YoutubeDl::new(url)
-    .cookies("cookies.txt")
-    .socket_timeout("15")
-    .extra_arg(format!("-S filesize:{}M", size_limit))
-    .output_template(outfile)
-    .download(true)
-    .to_owned();
+/// `7d` and friends, in seconds.
+fn parse_ttl(ttl: &str) -> Option<u64> {
+  let unit = match ttl.chars().last()? {
+    's' => 1,
+    'm' => 60,
+    'h' => 60 * 60,
+    'd' => 24 * 60 * 60,
+    'w' => 7 * 24 * 60 * 60,
+    _ => return None,
+  };
+  let count: u64 = ttl[..ttl.len() - 1].parse().ok()?;
+  count.checked_mul(unit).filter(|seconds| *seconds > 0)
+}
 
-  dl_cmd
+/// `key=value` words in a command are options rather than positional arguments.
+/// Urls have `=` in them too, but never in something that looks like a plain key.
+fn is_option(arg: &str) -> bool {
+  arg.split_once('=').is_some_and(|(key, _)| {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+  })
+}
+
+/// Where a hosted file can be seen, straight from the bucket if no route serves it.
+pub fn link(bucket: &str, key: &str) -> String {
+  config::get().http.link(bucket, key).unwrap_or_else(|| {
+    ProxyRoute {
+      bucket: bucket.to_owned(),
+      ..Default::default()
+    }
+    .url(key)
+  })
 }
 
 fn rand_string(len: usize) -> String {
@@ -327,8 +1362,159 @@ enum Command {
   Start,
   #[command(description = "extract audio from a video here.")]
   Audio(String),
-  #[command(description = "mirror a video here.")]
+  #[command(description = "mirror a video here. (<url> [size limit] [via=<route>])")]
   Video(String),
   #[command(description = "does... something?")]
   Song(String),
+  #[command(description = "host the file you're replying to on kota.is. ([ttl=7d])")]
+  Host(String),
+  #[command(
+    description = "mirror the pictures in a post or gallery. ([format=webp|avif] [reply=links|album])"
+  )]
+  Image(String),
+  #[command(description = "(admin) allow a user, chat or admin.")]
+  Allow(String),
+  #[command(description = "(admin) deny a user, chat or admin.")]
+  Deny(String),
+  #[command(description = "show your daily quota. (admin) set one.")]
+  Quota(String),
+  #[command(description = "mirror links posted here without a command. (on|off)")]
+  Automirror(String),
+  #[command(description = "change how I behave in this chat. (<setting> <value>)")]
+  Settings(String),
+  #[command(description = "list the files you've had me host, with delete buttons.")]
+  Mine,
+  #[command(description = "(admin) show jobs, traffic, cache and disk stats.")]
+  Stats,
+  #[command(description = "(admin) delete a hosted file.")]
+  Purge(String),
+  #[command(description = "(admin) clear the http file cache.")]
+  Cache(String),
+  #[command(description = "(admin) show when the certificate expires.")]
+  Cert,
+  #[command(description = "(admin) manage site cookies. (set|rm <domain>)")]
+  Cookies(String),
+  #[command(description = "(admin) update the downloader.")]
+  Ytdlp(String),
+}
+
+impl Command {
+  fn admin_only(&self) -> bool {
+    matches!(
+      self,
+      Self::Allow(_)
+        | Self::Deny(_)
+        | Self::Stats
+        | Self::Purge(_)
+        | Self::Cache(_)
+        | Self::Cert
+        | Self::Cookies(_)
+        | Self::Ytdlp(_)
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use parking_lot::Mutex;
+  use serde_json::{json, Value};
+  use std::sync::Arc;
+  use warp::Filter;
+
+  fn message(chat: i64) -> Value {
+    json!({
+      "message_id": 1,
+      "date": 0,
+      "chat": { "id": chat, "type": "private", "first_name": "test" },
+      "text": "test",
+    })
+  }
+
+  /// A bot talking to a stand-in for the telegram api, which answers everything and
+  /// keeps the names of the methods it was asked for.
+  fn telegram(chat: i64) -> (Bot, Arc<Mutex<Vec<String>>>) {
+    let calls = Arc::new(Mutex::new(vec![]));
+    let seen = calls.clone();
+    let api = warp::path::tail().map(move |tail: warp::path::Tail| {
+      let method = tail.as_str().rsplit('/').next().unwrap().to_lowercase();
+      let result = match method.as_str() {
+        "sendmediagroup" => json!([message(chat)]),
+        "deletemessage" => json!(true),
+        _ => message(chat),
+      };
+      seen.lock().push(method);
+      warp::reply::json(&json!({ "ok": true, "result": result }))
+    });
+    let (addr, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let bot = Bot::new("1:test").set_api_url(format!("http://{addr}").parse().unwrap());
+    (bot, calls)
+  }
+
+  /// Mirrors `url` through the fake backend, returning the telegram methods it used.
+  async fn mirrored(chat: i64, url: &str, kind: MirrorKind) -> Vec<String> {
+    crate::download::fake_fixtures();
+    std::fs::create_dir_all(tmp_dir()).unwrap();
+    let (bot, calls) = telegram(chat);
+    let msg = serde_json::from_value(message(chat)).unwrap();
+    mirror(bot, msg, url.to_owned(), kind).await;
+    let calls = calls.lock().clone();
+    calls
+  }
+
+  #[tokio::test]
+  async fn sends_a_video() {
+    let calls = mirrored(1, "fixture://clip.mp4", MirrorKind::Video).await;
+    assert_eq!(
+      calls,
+      [
+        "sendmessage",
+        "editmessagetext",
+        "sendvideo",
+        "deletemessage"
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn says_when_ttl_does_nothing() {
+    let calls = mirrored(6, "fixture://clip.mp4 ttl=1d", MirrorKind::Video).await;
+    assert_eq!(
+      calls,
+      [
+        "sendmessage",
+        "editmessagetext",
+        "sendvideo",
+        "sendmessage",
+        "deletemessage"
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn sends_audio_for_audio() {
+    let calls = mirrored(2, "fixture://clip.mp4", MirrorKind::Audio).await;
+    assert!(calls.contains(&"sendaudio".to_owned()), "{calls:?}");
+    assert!(!calls.contains(&"sendvideo".to_owned()), "{calls:?}");
+  }
+
+  #[tokio::test]
+  async fn sends_a_gallery_as_an_album() {
+    let calls = mirrored(3, "fixture://album", MirrorKind::Video).await;
+    assert!(calls.contains(&"sendmediagroup".to_owned()), "{calls:?}");
+  }
+
+  #[tokio::test]
+  async fn reports_a_login_failure_without_retrying() {
+    let calls = mirrored(4, "fixture://login", MirrorKind::Video).await;
+    assert_eq!(calls, ["sendmessage", "editmessagetext"]);
+  }
+
+  #[tokio::test]
+  async fn asks_for_a_missing_url() {
+    let calls = mirrored(5, "via=direct", MirrorKind::Video).await;
+    assert_eq!(calls, ["sendmessage"]);
+  }
 }
@@ -0,0 +1,53 @@
+use crate::messages::t;
+use crate::settings::ChatSettings;
+use std::{
+  path::Path,
+  sync::atomic::{AtomicU64, Ordering},
+};
+
+pub static JOBS: AtomicU64 = AtomicU64::new(0);
+pub static BYTES_SERVED: AtomicU64 = AtomicU64::new(0);
+pub static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+pub static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+/// The part of `CACHE_HITS` that didn't need the disk.
+pub static MEMORY_HITS: AtomicU64 = AtomicU64::new(0);
+
+pub fn incr(counter: &AtomicU64, by: u64) {
+  counter.fetch_add(by, Ordering::Relaxed);
+}
+
+/// A human readable summary of everything counted since startup.
+pub fn summary(settings: &ChatSettings, tmp_dir: &Path) -> String {
+  let hits = CACHE_HITS.load(Ordering::Relaxed);
+  let misses = CACHE_MISSES.load(Ordering::Relaxed);
+  let hit_rate = match hits + misses {
+    0 => 0.,
+    total => hits as f64 / total as f64 * 100.,
+  };
+
+  t(settings, "stats")
+    .arg("jobs", JOBS.load(Ordering::Relaxed))
+    .arg("mb", BYTES_SERVED.load(Ordering::Relaxed) / 1_000_000)
+    .arg("rate", format!("{hit_rate:.1}"))
+    .arg("hits", hits)
+    .arg("memory", MEMORY_HITS.load(Ordering::Relaxed))
+    .arg("misses", misses)
+    .arg("dir", tmp_dir.display())
+    .arg("disk", disk_usage(tmp_dir) / 1_000_000)
+    .into()
+}
+
+fn disk_usage(path: &Path) -> u64 {
+  let Ok(entries) = std::fs::read_dir(path) else {
+    return 0;
+  };
+
+  entries
+    .flatten()
+    .map(|entry| match entry.metadata() {
+      Ok(meta) if meta.is_dir() => disk_usage(&entry.path()),
+      Ok(meta) => meta.len(),
+      Err(_) => 0,
+    })
+    .sum()
+}
@@ -0,0 +1,101 @@
+use anyhow::Result;
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+};
+
+/// Where the bot keeps its files: the working directory, or a throwaway one
+/// under test so the real stores are left alone.
+pub fn state_dir() -> &'static Path {
+  #[cfg(test)]
+  {
+    static DIR: std::sync::OnceLock<tempfile::TempDir> = std::sync::OnceLock::new();
+    DIR.get_or_init(|| tempfile::TempDir::new().unwrap()).path()
+  }
+  #[cfg(not(test))]
+  Path::new("")
+}
+
+/// A value persisted as a json file in the state directory.
+/// Every `update` writes the whole value back to disk.
+pub struct Store<T> {
+  path: PathBuf,
+  data: RwLock<T>,
+  /// Bumped by every `update`, so a late write never replaces a newer one.
+  version: AtomicU64,
+  /// The version on disk, held while writing.
+  saved: Arc<Mutex<u64>>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default> Store<T> {
+  pub fn open(name: &str) -> Self {
+    let path = state_dir().join(name);
+    let data = match std::fs::read_to_string(&path) {
+      Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+        error!("Could not parse {}, starting fresh: {err}", path.display());
+        T::default()
+      }),
+      Err(_) => T::default(),
+    };
+
+    Self {
+      path,
+      data: RwLock::new(data),
+      version: AtomicU64::new(0),
+      saved: Arc::default(),
+    }
+  }
+
+  pub fn read(&self) -> RwLockReadGuard<'_, T> {
+    self.data.read()
+  }
+
+  /// Changes the value and saves it. Only serializing happens under the lock, the
+  /// file is written afterwards, off the async workers when there are some.
+  pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+    let (result, json, version) = {
+      let mut data = self.data.write();
+      let result = f(&mut data);
+      let version = self.version.fetch_add(1, Ordering::Relaxed) + 1;
+      (result, serde_json::to_vec_pretty(&*data), version)
+    };
+    let json = match json {
+      Ok(json) => json,
+      Err(err) => {
+        error!("Failed to save {}: {err:?}", self.path.display());
+        return result;
+      }
+    };
+
+    let (path, saved) = (self.path.clone(), self.saved.clone());
+    let save = move || {
+      if let Err(err) = save(&path, &saved, version, &json) {
+        error!("Failed to save {}: {err:?}", path.display());
+      }
+    };
+    match tokio::runtime::Handle::try_current() {
+      Ok(runtime) => drop(runtime.spawn_blocking(save)),
+      Err(_) => save(),
+    }
+    result
+  }
+}
+
+fn save(path: &Path, saved: &Mutex<u64>, version: u64, json: &[u8]) -> Result<()> {
+  let mut saved = saved.lock();
+  if *saved > version {
+    return Ok(());
+  }
+  // write then rename so a crash never leaves half a file behind
+  let mut tmp = path.as_os_str().to_owned();
+  tmp.push(".tmp");
+  std::fs::write(&tmp, json)?;
+  std::fs::rename(tmp, path)?;
+  *saved = version;
+  Ok(())
+}